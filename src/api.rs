@@ -1,6 +1,10 @@
-use crate::{db, fetch, State};
+use crate::cache;
+use crate::error::{ApiError, ItemError};
+use crate::resolve::{self, ResolveError};
+use crate::{db, fetch, stream, Currencies, Fields, State};
 use chrono::prelude::*;
 use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use chrono_tz::Tz;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
 use tide::http::mime;
@@ -8,12 +12,71 @@ use tide::{Body, Request, Response, Result};
 use tracing::{info, info_span, warn_span};
 
 pub type CurrentMarkets = HashMap<String, HashMap<String, f64>>;
+/// prices of the market keyed by the day
+pub type PeriodPrices = BTreeMap<String, BTreeMap<String, f64>>;
+/// aggregates of the market keyed by the first day of the interval
+pub type PeriodOhlc = BTreeMap<String, BTreeMap<String, db::Ohlc>>;
+
+#[derive(Debug, serde::Deserialize)]
+struct FieldsQuery {
+    fields: Option<String>,
+}
+
+/// series requested in `fields=price,market_cap,volume`, price by default
+fn query_fields(req: &Request<State>) -> std::result::Result<Fields, String> {
+    let query: FieldsQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return Err(e.to_string()),
+    };
+    match query.fields {
+        Some(x) => x
+            .parse()
+            .map_err(|e: Box<dyn std::error::Error>| e.to_string()),
+        None => Ok(Fields::default()),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CurrenciesQuery {
+    currencies: Option<String>,
+}
+
+/// currencies requested in `currencies=usd,eur`, all configured by default
+pub fn query_currencies(req: &Request<State>) -> std::result::Result<Currencies, String> {
+    let query: CurrenciesQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return Err(e.to_string()),
+    };
+    match query.currencies {
+        Some(x) => req.state().currencies.select(&x),
+        None => Ok(req.state().currencies.clone()),
+    }
+}
+
+/// current prices with only requested columns
+pub fn select_columns(
+    prices: &BTreeMap<String, f64>,
+    currencies: &Currencies,
+    fields: &Fields,
+) -> BTreeMap<String, f64> {
+    let columns = fields.columns(currencies);
+    prices
+        .iter()
+        .filter(|(k, _)| columns.contains(k))
+        .map(|(k, v)| (k.clone(), *v))
+        .collect()
+}
 
 pub async fn metrics(req: Request<State>) -> Result {
-    let val = fetch::current(&req.state().markets, &req.state().currencies);
+    let fields = match query_fields(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let state = req.state();
+    let val = fetch::current(&state.markets, &state.currencies, &fields);
     let markets: CurrentMarkets = serde_json::from_str(&val)?;
 
-    let out = crate::metrics::output(markets);
+    let out = crate::metrics::output(markets, &state.currencies, &fields);
     let mut res = Response::new(200);
     res.set_content_type(mime::PLAIN);
     res.set_body(Body::from_string(out));
@@ -30,13 +93,38 @@ pub async fn health(_: Request<State>) -> Result {
 }
 
 pub async fn current(req: Request<State>) -> Result {
+    let currencies = match query_currencies(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
     let mut res = Response::new(200);
-    let val = fetch::current(&req.state().markets, &req.state().currencies);
-    res.set_body(val.as_str());
+    let val = fetch::current(
+        &req.state().markets,
+        &req.state().currencies,
+        &Fields::default(),
+    );
+    res.insert_ext(cache::Policy::MaxAge(snapshot_max_age(
+        req.state(),
+        &Fields::default(),
+    )));
+    if currencies == req.state().currencies {
+        res.set_body(val.as_str());
+        return Ok(res);
+    }
+    // the snapshot of all currencies is shared in the cache
+    let markets: BTreeMap<String, BTreeMap<String, f64>> = serde_json::from_str(&val)?;
+    let filtered: BTreeMap<String, BTreeMap<String, f64>> = markets
+        .iter()
+        .map(|(m, prices)| {
+            let prices = select_columns(prices, &currencies, &Fields::default());
+            (m.clone(), prices)
+        })
+        .collect();
+    res.set_body(serde_json::to_string(&filtered)?);
     Ok(res)
 }
 
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug, Default, serde::Serialize, schemars::JsonSchema)]
 pub struct HistoryResponse {
     pub markets: HashMap<String, BTreeMap<String, f64>>,
 }
@@ -46,109 +134,1339 @@ impl HistoryResponse {
         m.insert(market.to_owned(), prices);
         Self { markets: m }
     }
+    pub fn insert(&mut self, market: &str, prices: BTreeMap<String, f64>) {
+        self.markets.insert(market.to_owned(), prices);
+    }
 }
 
 fn internal_error(msg: &str) -> Result {
-    let mut res = Response::new(500);
-    let mut mm: HashMap<String, String> = HashMap::new();
-    mm.insert("error".to_string(), msg.to_string());
-    res.set_body(serde_json::to_string(&mm)?);
-    return Ok(res);
+    Err(ApiError::internal(msg).into())
 }
 
 fn input_error(msg: &str) -> Result {
-    let mut res = Response::new(400);
-    let mut mm: HashMap<String, String> = HashMap::new();
-    mm.insert("error".to_string(), msg.to_string());
-    res.set_body(serde_json::to_string(&mm)?);
-    return Ok(res);
+    Err(ApiError::input(msg).into())
+}
+
+fn resolve_error(e: ResolveError) -> Result {
+    Err(ApiError::from(e).into())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+pub async fn search(req: Request<State>) -> Result {
+    let query: SearchQuery = req.query()?;
+    let q = query.q.unwrap_or_default();
+    if q.trim().is_empty() {
+        return input_error("missing q parameter");
+    }
+    let limit = query.limit.unwrap_or(25).min(250);
+    let found = resolve::search(&req.state().markets, &q, limit);
+    let mut res = Response::new(200);
+    res.set_body(Body::from_json(&found)?);
+    Ok(res)
 }
 
 pub async fn history(req: Request<State>) -> Result {
-    let market = req.param("market").unwrap_or("none");
-    let iso8601 = req.param("date").unwrap_or("none");
-    let today = Utc::now().format("%Y-%m-%d").to_string();
+    let state = req.state();
+    let market = match resolve::market(&state.markets, &state.aliases, req.param("market")?) {
+        Ok(x) => x.name,
+        Err(e) => return resolve_error(e),
+    };
+    market_history(&req, market.as_str()).await
+}
 
-    if iso8601 == today {
-        let val = fetch::current(&req.state().markets, &req.state().currencies);
-        let markets: BTreeMap<String, BTreeMap<String, f64>> = match serde_json::from_str(&val) {
-            Ok(x) => x,
-            Err(e) => {
-                warn_span!("parse_failure", e=%e, dt=%iso8601, market=%market)
-                    .in_scope(|| info!("current"));
-                return internal_error(&e.to_string());
-            }
-        };
-        let prices = match markets.get(market) {
-            Some(x) => x.clone(),
-            None => {
-                warn_span!("no_market", dt=%iso8601, market=%market).in_scope(|| info!("current"));
-                return input_error("no such market");
-            }
-        };
+pub async fn token_history(req: Request<State>) -> Result {
+    let chain = req.param("chain")?;
+    let address = req.param("address")?;
+    let market = match req.state().markets.get_contract(chain, address) {
+        Some(x) => x.name.clone(),
+        None => {
+            let e = ApiError::not_found("token_not_configured", "token is not configured");
+            return Err(e.into());
+        }
+    };
+    market_history(&req, market.as_str()).await
+}
+
+/// prices of the market for the day in the negotiated format
+fn history_response(
+    format: stream::Format,
+    columns: &[String],
+    market: &str,
+    date: &str,
+    prices: BTreeMap<String, f64>,
+    policy: cache::Policy,
+) -> Result {
+    let mut res = Response::new(200);
+    res.insert_ext(policy);
+    // the format is negotiated from `Accept` unless it is in the query
+    res.append_header("vary", "accept");
+    if format == stream::Format::Json {
         let response = HistoryResponse::new(market, prices);
-        let mut res = Response::new(200);
         res.set_body(serde_json::to_string(&response)?);
         return Ok(res);
     }
+    let row = (date.to_owned(), prices);
+    let out = stream::header(format, columns) + &stream::line(format, columns, &row);
+    res.set_body(out);
+    res.set_content_type(format.mime());
+    Ok(res)
+}
+
+async fn market_history(req: &Request<State>, market: &str) -> Result {
+    let fields = match query_fields(req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let format = match stream::negotiate(req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let currencies = match query_currencies(req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let columns = fields.columns(&currencies);
+    let iso8601 = req.param("date").unwrap_or("none");
+    let day = match query_tz(req).and_then(|tz| parse_day(iso8601, tz)) {
+        Ok(x) => x,
+        Err(e) => {
+            warn_span!("invalid_date", e=%e, dt=%iso8601, market=%market)
+                .in_scope(|| info!("history"));
+            return input_error(&e);
+        }
+    };
+    // whether the row of the requested day itself is stored
+    let mut complete = true;
+    let mut approximate = false;
+    let prices = match day {
+        Day::Today => {
+            let markets = match current_markets(req.state(), &fields) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn_span!("parse_failure", e=%e, dt=%iso8601, market=%market)
+                        .in_scope(|| info!("current"));
+                    return internal_error(&e);
+                }
+            };
+            match markets.get(market) {
+                Some(x) => select_columns(x, &currencies, &fields),
+                None => {
+                    warn_span!("no_market", dt=%iso8601, market=%market)
+                        .in_scope(|| info!("current"));
+                    return input_error("no such market");
+                }
+            }
+        }
+        Day::Past(tm) => {
+            info_span!("requested", dt=%iso8601, market=%market).in_scope(|| info!("history"));
+            let db_pool = req.state().db_pool.clone();
+            let mut conn = db_pool.acquire().await?;
+            match db::get_prices(&mut conn, tm, market, &currencies, &fields).await {
+                Some((ts, prices)) => {
+                    complete = ts == tm;
+                    prices
+                }
+                None => {
+                    complete = false;
+                    BTreeMap::new()
+                }
+            }
+        }
+        Day::Local(tm, utc) => {
+            if fields != Fields::default() {
+                return input_error("only prices are available with tz");
+            }
+            info_span!("requested", dt=%iso8601, tm=%tm, market=%market)
+                .in_scope(|| info!("history"));
+            let mut conn = req.state().db_pool.acquire().await?;
+            let local = local_prices(&mut conn, tm, utc, market, &currencies).await?;
+            complete = local.complete;
+            approximate = local.approximate;
+            local.prices
+        }
+    };
+    let policy = day_policy(req.state(), &day, &fields, complete);
+    let mut res = history_response(format, &columns, market, iso8601, prices, policy)?;
+    if approximate {
+        res.insert_header(APPROXIMATE_HEADER, "daily");
+    }
+    Ok(res)
+}
+
+/// response header of the local days answered with the daily prices of the date
+pub const APPROXIMATE_HEADER: &str = "x-approximate";
+
+/// prices at the start of the local day
+struct LocalPrices {
+    prices: BTreeMap<String, f64>,
+    /// the row of the requested hour or day itself was found
+    complete: bool,
+    /// the hour is not stored, prices are of the UTC day of the same date
+    approximate: bool,
+}
+
+/// prices of the local day from the hourly prices. hours are only kept for the recent days,
+/// other days fall back to the daily row of the same date
+async fn local_prices(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::Postgres>,
+    tm: DateTime<Utc>,
+    utc: DateTime<Utc>,
+    market: &str,
+    currencies: &Currencies,
+) -> anyhow::Result<LocalPrices> {
+    if let Some(prices) = db::get_hourly(conn, hour_start(tm), market, currencies).await? {
+        return Ok(LocalPrices {
+            prices,
+            complete: true,
+            approximate: false,
+        });
+    }
+    let row = db::get_prices(conn, utc, market, currencies, &Fields::default()).await;
+    // recent hours may be stored later, older ones are not kept by the provider
+    let kept = Utc::now() - chrono::Duration::days(fetch::HOURLY_DAYS);
+    Ok(LocalPrices {
+        complete: tm < kept && matches!(&row, Some((ts, _)) if *ts == utc),
+        prices: row.map(|x| x.1).unwrap_or_default(),
+        approximate: true,
+    })
+}
+
+/// seconds until the current snapshot is refreshed
+fn snapshot_max_age(state: &State, fields: &Fields) -> i64 {
+    let (_, fetched_at) = fetch::current_at(&state.markets, &state.currencies, fields);
+    fetch::CACHE_SECS - (Utc::now() - fetched_at).num_seconds()
+}
+
+/// caching of the day: stored rows of the finished days and stored hours don't change.
+/// complete is whether the row of the requested day or hour itself was found
+fn day_policy(state: &State, day: &Day, fields: &Fields, complete: bool) -> cache::Policy {
+    match day {
+        Day::Today => cache::Policy::MaxAge(snapshot_max_age(state, fields)),
+        _ if !complete => cache::Policy::MaxAge(fetch::CACHE_SECS),
+        Day::Past(_) | Day::Local(_, _) => cache::Policy::Immutable,
+    }
+}
+
+/// number of days of the period, both ends included
+fn period_days(tm_from: DateTime<Utc>, tm_to: DateTime<Utc>) -> i64 {
+    (tm_to - tm_from).num_days() + 1
+}
+
+/// caching of the period ending at the day, complete when each day of it is stored
+fn period_policy(tm_to: DateTime<Utc>, complete: bool) -> cache::Policy {
+    if complete && tm_to < Utc::today().and_hms(0, 0, 0) {
+        cache::Policy::Immutable
+    } else {
+        cache::Policy::MaxAge(fetch::CACHE_SECS)
+    }
+}
+
+/// requested day: today is served from the current snapshot, past days from the database.
+/// days in other time zones start between the daily rows, they are served from the hourly prices,
+/// kept with the start of the UTC day of the same date
+#[derive(Clone, Debug, PartialEq)]
+enum Day {
+    Today,
+    Past(DateTime<Utc>),
+    Local(DateTime<Utc>, DateTime<Utc>),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TzQuery {
+    tz: Option<String>,
+}
+
+/// time zone of the day boundaries from `tz=Europe/Berlin`, none for UTC
+fn query_tz(req: &Request<State>) -> std::result::Result<Option<Tz>, String> {
+    let query: TzQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return Err(e.to_string()),
+    };
+    match query.tz {
+        None => Ok(None),
+        Some(x) => match x.parse::<Tz>() {
+            Ok(Tz::UTC) | Ok(Tz::Etc__UTC) => Ok(None),
+            Ok(tz) => Ok(Some(tz)),
+            Err(e) => Err(format!("tz: {}", e)),
+        },
+    }
+}
+
+/// endpoints of the UTC days reject other time zones rather than ignore them
+fn utc_only(req: &Request<State>) -> std::result::Result<(), ApiError> {
+    match query_tz(req) {
+        Ok(None) => Ok(()),
+        Ok(Some(tz)) => {
+            let msg = format!(
+                "tz {} is only supported by the prices of the single day",
+                tz.name()
+            );
+            let status = tide::StatusCode::BadRequest;
+            Err(ApiError::new(status, "tz_not_supported", &msg))
+        }
+        Err(e) => Err(ApiError::input(&e)),
+    }
+}
+
+/// start of the hour containing the instant, as the hourly prices are stored
+fn hour_start(tm: DateTime<Utc>) -> DateTime<Utc> {
+    tm.date().and_hms(tm.hour(), 0, 0)
+}
+
+/// instant of the start of the local day, first hour that exists when midnight is skipped
+fn local_day_start(tz: Tz, dt: NaiveDate) -> std::result::Result<DateTime<Utc>, String> {
+    for hour in 0..3 {
+        if let Some(x) = tz.from_local_datetime(&dt.and_hms(hour, 0, 0)).earliest() {
+            return Ok(x.with_timezone(&Utc));
+        }
+    }
+    Err(format!("no start of {} in {}", dt, tz.name()))
+}
+
+fn parse_day(iso8601: &str, tz: Option<Tz>) -> std::result::Result<Day, String> {
+    if let Some(tz) = tz {
+        let dt = match NaiveDate::parse_from_str(iso8601, "%Y-%m-%d") {
+            Ok(x) => x,
+            Err(e) => return Err(format!("{}: {}", iso8601, e)),
+        };
+        let tm = local_day_start(tz, dt)?;
+        if tm > Utc::now() {
+            return Err("no prices for future".to_owned());
+        }
+        return Ok(Day::Local(tm, Utc.from_utc_date(&dt).and_hms(0, 0, 0)));
+    }
+    let today = Utc::now().format("%Y-%m-%d").to_string();
+    if iso8601 == today {
+        return Ok(Day::Today);
+    }
     if let Ordering::Less = today.cmp(&iso8601.to_string()) {
-        warn_span!("future", dt=%iso8601, market=%market).in_scope(|| info!("current"));
-        return input_error("no prices for future");
+        return Err("no prices for future".to_owned());
+    }
+    day_start(iso8601).map(Day::Past)
+}
+
+/// current snapshot of all markets
+fn current_markets(
+    state: &State,
+    fields: &Fields,
+) -> std::result::Result<BTreeMap<String, BTreeMap<String, f64>>, String> {
+    let val = fetch::current(&state.markets, &state.currencies, fields);
+    serde_json::from_str(&val).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct MarketsQuery {
+    markets: Option<String>,
+}
+
+/// markets requested in `markets=bitcoin,eth`, all configured by default
+pub fn query_markets(req: &Request<State>) -> std::result::Result<Vec<String>, ApiError> {
+    let state = req.state();
+    let query: MarketsQuery = req.query().map_err(|e| ApiError::input(&e.to_string()))?;
+    let list = match query.markets {
+        Some(x) => x,
+        None => return Ok(state.markets.as_vec()),
+    };
+    let mut out: Vec<String> = vec![];
+    for name in list.split(",").filter(|x| !x.trim().is_empty()) {
+        let market =
+            resolve::market(&state.markets, &state.aliases, name).map_err(ApiError::from)?;
+        if !out.contains(&market.name) {
+            out.push(market.name);
+        }
+    }
+    Ok(out)
+}
+
+/// prices of several markets for the day
+pub async fn multi_history(req: Request<State>) -> Result {
+    let markets = match query_markets(&req) {
+        Ok(x) => x,
+        Err(e) => return Err(e.into()),
+    };
+    let fields = match query_fields(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let currencies = match query_currencies(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let iso8601 = req.param("date").unwrap_or("none");
+    let day = match query_tz(&req).and_then(|tz| parse_day(iso8601, tz)) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    info!("markets={} dt={}", markets.join(","), iso8601);
+
+    let mut response = HistoryResponse::default();
+    // whether the rows of the requested day itself are stored for every market
+    let mut complete = true;
+    let mut approximate = false;
+    match day.clone() {
+        Day::Today => {
+            let current = match current_markets(req.state(), &fields) {
+                Ok(x) => x,
+                Err(e) => return internal_error(&e),
+            };
+            for market in &markets {
+                if let Some(prices) = current.get(market) {
+                    response.insert(market, select_columns(prices, &currencies, &fields));
+                }
+            }
+        }
+        Day::Past(tm) => {
+            let mut conn = req.state().db_pool.acquire().await?;
+            for market in &markets {
+                let row = db::get_prices(&mut conn, tm, market, &currencies, &fields).await;
+                complete = complete && matches!(&row, Some((ts, _)) if *ts == tm);
+                response.insert(market, row.map(|x| x.1).unwrap_or_default());
+            }
+        }
+        Day::Local(tm, utc) => {
+            if fields != Fields::default() {
+                return input_error("only prices are available with tz");
+            }
+            let mut conn = req.state().db_pool.acquire().await?;
+            for market in &markets {
+                let local = local_prices(&mut conn, tm, utc, market, &currencies).await?;
+                complete = complete && local.complete;
+                approximate = approximate || local.approximate;
+                response.insert(market, local.prices);
+            }
+        }
     }
+    let mut res = Response::new(200);
+    res.insert_ext(day_policy(req.state(), &day, &fields, complete));
+    if approximate {
+        res.insert_header(APPROXIMATE_HEADER, "daily");
+    }
+    res.set_body(serde_json::to_string(&response)?);
+    Ok(res)
+}
 
-    let dt = match NaiveDate::parse_from_str(iso8601, "%Y-%m-%d") {
+/// stored prices of several markets for the period, keyed by market and date
+pub async fn multi_period(req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let markets = match query_markets(&req) {
         Ok(x) => x,
-        Err(e) => return input_error(&format!("{:?}", e)),
+        Err(e) => return Err(e.into()),
     };
-    let y = dt.year();
-    let m = dt.month();
-    let d = dt.day();
-    let tm: DateTime<Utc> = Utc.ymd(y, m, d).and_hms(0, 0, 0);
-    info_span!("requested", y=%y, m=%m, d=%d, dt=%dt, market=%market).in_scope(|| info!("history"));
+    let fields = match query_fields(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let currencies = match query_currencies(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let (tm_from, tm_to) = match period_range(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let max_days = req.state().max_period_days;
+    if (tm_to - tm_from).num_days() >= max_days {
+        return input_error(&format!("period is longer than {} days", max_days));
+    }
+    info!(
+        "markets={} from={} to={}",
+        markets.join(","),
+        tm_from,
+        tm_to
+    );
+
+    let mut conn = req.state().db_pool.acquire().await?;
+    let mut result: BTreeMap<String, BTreeMap<String, BTreeMap<String, f64>>> = BTreeMap::new();
+    for market in &markets {
+        let prices =
+            db::get_prices_period(&mut conn, tm_from, tm_to, market, &currencies, &fields).await;
+        result.insert(market.clone(), prices);
+    }
+    let days = period_days(tm_from, tm_to);
+    let complete = result.values().all(|x| x.len() as i64 == days);
+    let mut res = Response::new(200);
+    res.insert_ext(period_policy(tm_to, complete));
+    res.set_body(serde_json::to_string(&result)?);
+    Ok(res)
+}
+
+/// `from` and `to` days of the period from the path
+fn period_range(
+    req: &Request<State>,
+) -> std::result::Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    let tm_from = match day_start(req.param("from").unwrap_or("none")) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("from error: {}", e)),
+    };
+    let tm_to = match day_start(req.param("to").unwrap_or("none")) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("to error: {}", e)),
+    };
+    Ok((tm_from, tm_to))
+}
 
+#[derive(Debug, serde::Deserialize)]
+struct PeriodQuery {
+    /// `week`, `month` or `year` to aggregate the days into buckets
+    interval: Option<String>,
+    /// rows per page, enables pagination
+    limit: Option<i64>,
+    /// date of the first row of the page, taken from the `next` link
+    cursor: Option<String>,
+    /// stream the whole period without limits
+    stream: Option<bool>,
+}
+
+/// link to the page of the period starting from the cursor date
+fn next_link(req: &Request<State>, cursor: &str, limit: i64) -> String {
+    let mut url = req.url().clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| k != "cursor" && k != "limit")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("limit", &limit.to_string())
+        .append_pair("cursor", cursor);
+    format!("{}?{}", url.path(), url.query().unwrap_or(""))
+}
+
+pub async fn period(req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let state = req.state();
+    let market = match resolve::market(&state.markets, &state.aliases, req.param("market")?) {
+        Ok(x) => x.name,
+        Err(e) => return resolve_error(e),
+    };
+    let market = market.as_str();
+    let fields = match query_fields(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let currencies = match query_currencies(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let (tm_from, tm_to) = match period_range(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    info!("market={} from={} to={}", market, tm_from, tm_to);
+
+    let query: PeriodQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return input_error(&e.to_string()),
+    };
+    let format = match stream::negotiate(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let max_days = state.max_period_days;
+    let streamed = query.stream.unwrap_or(false);
+    if format != stream::Format::Json || streamed {
+        if query.interval.is_some() {
+            return input_error("interval is not available in streams");
+        }
+        // tables are not paged, stream=true lifts the limit of the period
+        if !streamed && (tm_to - tm_from).num_days() >= max_days {
+            return input_error(&format!(
+                "period is longer than {} days, use stream=true",
+                max_days
+            ));
+        }
+        let rows = db::stream_prices_period(
+            state.db_pool.clone(),
+            tm_from,
+            tm_to,
+            market,
+            &currencies,
+            &fields,
+        );
+        let mut res = Response::new(200);
+        res.append_header("vary", "accept");
+        res.set_body(stream::body(rows, format, fields.columns(&currencies)));
+        return Ok(res);
+    }
     let db_pool = req.state().db_pool.clone();
     let mut conn = db_pool.acquire().await?;
-    let prices = db::get_prices(&mut conn, tm, market, &req.state().currencies).await;
+    if let Some(interval) = query.interval {
+        let interval: db::Interval = match interval.parse() {
+            Ok(x) => x,
+            Err(e) => return input_error(&e),
+        };
+        let result = db::get_prices_aggregated(
+            &mut conn,
+            tm_from,
+            tm_to,
+            interval,
+            market,
+            &currencies,
+            &fields,
+        )
+        .await?;
+        let stored = db::count_prices(&mut conn, tm_from, tm_to, market).await?;
+        let mut res = Response::new(200);
+        res.insert_ext(period_policy(tm_to, stored == period_days(tm_from, tm_to)));
+        res.append_header("vary", "accept");
+        res.set_body(serde_json::to_string(&result)?);
+        return Ok(res);
+    }
+
+    let limit = match query.limit {
+        Some(x) if x < 1 || x > max_days => {
+            return input_error(&format!("limit should be between 1 and {}", max_days))
+        }
+        Some(x) => x,
+        None if (tm_to - tm_from).num_days() >= max_days => {
+            return input_error(&format!(
+                "period is longer than {} days, use limit or stream=true",
+                max_days
+            ))
+        }
+        None => max_days,
+    };
+    let tm_page = match &query.cursor {
+        Some(x) => match day_start(x) {
+            Ok(x) => x.max(tm_from),
+            Err(e) => return input_error(&format!("cursor error: {}", e)),
+        },
+        None => tm_from,
+    };
+    let (result, next) = db::get_prices_page(
+        &mut conn,
+        tm_page,
+        tm_to,
+        limit,
+        market,
+        &currencies,
+        &fields,
+    )
+    .await?;
+
+    // the page is complete when it has each day from its start to the next page or the end
+    let page_end = match &next {
+        Some(cursor) => match day_start(cursor) {
+            Ok(x) => x - chrono::Duration::days(1),
+            Err(e) => return internal_error(&e),
+        },
+        None => tm_to,
+    };
+    let complete = result.len() as i64 == period_days(tm_page, page_end);
+    let mut res = Response::new(200);
+    res.insert_ext(period_policy(page_end, complete));
+    res.append_header("vary", "accept");
+    if let Some(cursor) = next {
+        let link = next_link(&req, &cursor, limit);
+        res.insert_header("link", format!("<{}>; rel=\"next\"", link));
+    }
+    res.set_body(serde_json::to_string(&result)?);
+    Ok(res)
+}
+
+/// side of the conversion: fiat currency or one of the markets
+#[derive(Clone, Debug, PartialEq)]
+enum Asset {
+    Currency(String),
+    Market(String),
+}
+
+impl Asset {
+    fn name(&self) -> &str {
+        match self {
+            Asset::Currency(x) => x.as_str(),
+            Asset::Market(x) => x.as_str(),
+        }
+    }
+}
+
+fn asset(state: &State, query: &str) -> std::result::Result<Asset, ResolveError> {
+    let q = query.trim().to_lowercase();
+    if state.currencies.contains(&q) {
+        return Ok(Asset::Currency(q));
+    }
+    resolve::market(&state.markets, &state.aliases, &q).map(|m| Asset::Market(m.name))
+}
+
+/// moment of the prices: the current snapshot or stored history
+#[derive(Clone, Debug, PartialEq)]
+enum At {
+    Now,
+    Time(DateTime<Utc>),
+}
+
+/// accepts `YYYY-MM-DD` (start of the day in UTC) or unix timestamp
+fn parse_at(src: Option<&str>) -> std::result::Result<At, String> {
+    let src = match src {
+        Some(x) if x != "now" => x,
+        _ => return Ok(At::Now),
+    };
+    let tm = if let Ok(unix) = src.parse::<i64>() {
+        match Utc.timestamp_opt(unix, 0).single() {
+            Some(x) => x,
+            None => return Err(format!("at: timestamp {} is out of range", unix)),
+        }
+    } else {
+        let dt = match NaiveDate::parse_from_str(src, "%Y-%m-%d") {
+            Ok(x) => x,
+            Err(e) => return Err(format!("at: {}", e)),
+        };
+        Utc.ymd(dt.year(), dt.month(), dt.day()).and_hms(0, 0, 0)
+    };
+    let now = Utc::now();
+    if tm > now {
+        return Err("no prices for future".to_owned());
+    }
+    if tm.date() == now.date() {
+        return Ok(At::Now);
+    }
+    Ok(At::Time(tm))
+}
+
+/// price of the market in the currency, with the time of the price
+/// days a stored price is used for a later conversion time
+const MAX_PRICE_AGE_DAYS: i64 = 2;
+
+async fn market_price(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::Postgres>,
+    state: &State,
+    market: &str,
+    currency: &str,
+    at: &At,
+) -> std::result::Result<(DateTime<Utc>, f64), String> {
+    let (ts, prices) = match at {
+        At::Now => {
+            let val = fetch::current(&state.markets, &state.currencies, &Fields::default());
+            let markets: CurrentMarkets = match serde_json::from_str(&val) {
+                Ok(x) => x,
+                Err(e) => return Err(e.to_string()),
+            };
+            match markets.get(market) {
+                Some(x) => (Utc::now(), x.clone().into_iter().collect()),
+                None => return Err(format!("no current prices for {}", market)),
+            }
+        }
+        At::Time(tm) => match db::get_prices_at(conn, *tm, market, &state.currencies).await {
+            // the last stored day before a gap in the index is not a price
+            Ok(Some((ts, _))) if *tm - ts > chrono::Duration::days(MAX_PRICE_AGE_DAYS) => {
+                return Err(format!(
+                    "no prices for {} at {}, last at {}",
+                    market, tm, ts
+                ))
+            }
+            Ok(Some(x)) => x,
+            Ok(None) => return Err(format!("no prices for {} at {}", market, tm)),
+            Err(e) => return Err(e.to_string()),
+        },
+    };
+    // gaps in the history are stored with negative prices
+    match prices.get(currency) {
+        Some(x) if *x > 0.0 => Ok((ts, *x)),
+        _ => Err(format!("no {} price for {} at {}", currency, market, ts)),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ConvertQuery {
+    amount: Option<f64>,
+    from: Option<String>,
+    to: Option<String>,
+    at: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+pub struct ConvertResponse {
+    pub amount: f64,
+    pub from: String,
+    pub to: String,
+    pub rate: f64,
+    pub result: f64,
+    /// time of the oldest price used for the rate
+    pub ts: String,
+}
+
+pub async fn convert(req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let query: ConvertQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return input_error(&e.to_string()),
+    };
+    let state = req.state();
+    let amount = query.amount.unwrap_or(1.0);
+    let from = match query.from.as_deref().map(|x| asset(state, x)) {
+        Some(Ok(x)) => x,
+        Some(Err(e)) => return resolve_error(e),
+        None => return input_error("from is required"),
+    };
+    let to = match query.to.as_deref().map(|x| asset(state, x)) {
+        Some(Ok(x)) => x,
+        Some(Err(e)) => return resolve_error(e),
+        None => return input_error("to is required"),
+    };
+    let at = match parse_at(query.at.as_deref()) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    // market to market is priced in the first currency,
+    // currency to currency is priced by the first market
+    let pivot_currency = state.currencies.iter().next().cloned().unwrap_or_default();
+    let pivot_market = state
+        .markets
+        .iter()
+        .next()
+        .map(|m| m.name.clone())
+        .unwrap_or_default();
 
+    let mut conn = state.db_pool.acquire().await?;
+    let (a, b) = match (&from, &to) {
+        (Asset::Market(m), Asset::Currency(c)) => (
+            market_price(&mut conn, state, m, c, &at).await,
+            Ok((Utc::now(), 1.0)),
+        ),
+        (Asset::Currency(c), Asset::Market(m)) => (
+            Ok((Utc::now(), 1.0)),
+            market_price(&mut conn, state, m, c, &at).await,
+        ),
+        (Asset::Market(m1), Asset::Market(m2)) => (
+            market_price(&mut conn, state, m1, &pivot_currency, &at).await,
+            market_price(&mut conn, state, m2, &pivot_currency, &at).await,
+        ),
+        (Asset::Currency(c1), Asset::Currency(c2)) => (
+            market_price(&mut conn, state, &pivot_market, c2, &at).await,
+            market_price(&mut conn, state, &pivot_market, c1, &at).await,
+        ),
+    };
+    let (ts_a, rate_a) = match a {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let (ts_b, rate_b) = match b {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let rate = rate_a / rate_b;
+    let response = ConvertResponse {
+        amount,
+        from: from.name().to_owned(),
+        to: to.name().to_owned(),
+        rate,
+        result: amount * rate,
+        ts: ts_a.min(ts_b).to_rfc3339(),
+    };
     let mut res = Response::new(200);
-    let response = HistoryResponse::new(market, prices);
     res.set_body(serde_json::to_string(&response)?);
     Ok(res)
 }
 
-pub async fn period(req: Request<State>) -> Result {
-    let market = req.param("market").unwrap_or("none");
-    let iso8601from = req.param("from").unwrap_or("none");
-    let from = match NaiveDate::parse_from_str(iso8601from, "%Y-%m-%d") {
+/// start of the day in UTC from `YYYY-MM-DD`
+pub fn day_start(src: &str) -> std::result::Result<DateTime<Utc>, String> {
+    match NaiveDate::parse_from_str(src, "%Y-%m-%d") {
+        Ok(dt) => Ok(Utc.ymd(dt.year(), dt.month(), dt.day()).and_hms(0, 0, 0)),
+        Err(e) => Err(format!("{}: {}", src, e)),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CrossQuery {
+    pivot: Option<String>,
+}
+
+/// market, base market and fiat pivot of the cross rate request
+fn cross_params(req: &Request<State>) -> std::result::Result<(String, String, String), ApiError> {
+    let state = req.state();
+    let market = resolve::market(
+        &state.markets,
+        &state.aliases,
+        req.param("market").unwrap_or(""),
+    )
+    .map_err(ApiError::from)?;
+    let base = resolve::market(
+        &state.markets,
+        &state.aliases,
+        req.param("base").unwrap_or(""),
+    )
+    .map_err(ApiError::from)?;
+    let query: CrossQuery = req.query().map_err(|e| ApiError::input(&e.to_string()))?;
+    let pivot = match query.pivot {
+        Some(x) => x.to_lowercase(),
+        None => state.currencies.iter().next().cloned().unwrap_or_default(),
+    };
+    if !state.currencies.contains(&pivot) {
+        return Err(ApiError::input(&format!(
+            "unknown pivot currency {}",
+            pivot
+        )));
+    }
+    Ok((market.name, base.name, pivot))
+}
+
+pub async fn cross_history(req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let (market, base, pivot) = match cross_params(&req) {
         Ok(x) => x,
-        Err(e) => {
-            let mut res = Response::new(400);
-            res.set_body(format!("from error: {:?}", e));
-            return Ok(res);
+        Err(e) => return Err(e.into()),
+    };
+    let state = req.state();
+    let at = match parse_at(req.param("date").ok()) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let mut conn = state.db_pool.acquire().await?;
+    let rate = match &at {
+        At::Now => {
+            let a = market_price(&mut conn, state, &market, &pivot, &at).await;
+            let b = market_price(&mut conn, state, &base, &pivot, &at).await;
+            match (a, b) {
+                (Ok((_, a)), Ok((_, b))) => Some(a / b),
+                _ => None,
+            }
+        }
+        At::Time(tm) => {
+            // rows are stored at the start of the day
+            let day = tm.date().and_hms(0, 0, 0);
+            let rates = db::get_cross_rates(&mut conn, day, day, &market, &base, &pivot).await?;
+            rates.values().next().cloned()
         }
     };
-    let tm_from: DateTime<Utc> = Utc
-        .ymd(from.year(), from.month(), from.day())
-        .and_hms(0, 0, 0);
+    let rate = match rate {
+        Some(x) => x,
+        None => return input_error(&format!("no {} rate for {}", base, market)),
+    };
+    let mut prices = BTreeMap::new();
+    prices.insert(base, rate);
+    let response = HistoryResponse::new(&market, prices);
+    let mut res = Response::new(200);
+    res.set_body(serde_json::to_string(&response)?);
+    Ok(res)
+}
 
-    let iso8601to = req.param("to").unwrap_or("none");
-    let to = match NaiveDate::parse_from_str(iso8601to, "%Y-%m-%d") {
+pub async fn cross_period(req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let (market, base, pivot) = match cross_params(&req) {
         Ok(x) => x,
-        Err(e) => {
-            let mut res = Response::new(400);
-            res.set_body(format!("to error; {:?}", e));
-            return Ok(res);
-        }
+        Err(e) => return Err(e.into()),
+    };
+    let (tm_from, tm_to) = match period_range(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
     };
-    let tm_to: DateTime<Utc> = Utc.ymd(to.year(), to.month(), to.day()).and_hms(0, 0, 0);
-    info!("market={} from={} to={}", market, from, to);
+    info!(
+        "market={} base={} from={} to={}",
+        market, base, tm_from, tm_to
+    );
 
-    let db_pool = req.state().db_pool.clone();
-    let mut conn = db_pool.acquire().await?;
-    let result =
-        db::get_prices_period(&mut conn, tm_from, tm_to, market, &req.state().currencies).await;
+    let mut conn = req.state().db_pool.acquire().await?;
+    let rates = db::get_cross_rates(&mut conn, tm_from, tm_to, &market, &base, &pivot).await?;
+    let result: BTreeMap<String, BTreeMap<String, f64>> = rates
+        .into_iter()
+        .map(|(ymd, rate)| {
+            let mut m = BTreeMap::new();
+            m.insert(base.clone(), rate);
+            (ymd, m)
+        })
+        .collect();
+    let mut res = Response::new(200);
+    res.set_body(serde_json::to_string(&result)?);
+    Ok(res)
+}
+
+const MAX_BATCH_ITEMS: usize = 1000;
+
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct BatchItem {
+    pub market: String,
+    pub date: String,
+    pub currency: Option<String>,
+}
 
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct BatchRequest {
+    pub items: Vec<BatchItem>,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, schemars::JsonSchema)]
+pub struct BatchResult {
+    pub market: String,
+    pub date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prices: Option<BTreeMap<String, f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ItemError>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+pub struct BatchResponse {
+    pub items: Vec<BatchResult>,
+}
+
+/// answers many (market, date) lookups with one query per market,
+/// failures are reported per item
+pub async fn batch(mut req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let body: BatchRequest = match req.body_json().await {
+        Ok(x) => x,
+        Err(e) => return input_error(&e.to_string()),
+    };
+    if body.items.len() > MAX_BATCH_ITEMS {
+        return input_error(&format!("too many items, max {}", MAX_BATCH_ITEMS));
+    }
+    let state = req.state();
+
+    // validating items, keeping resolved market and time of each one
+    let mut results: Vec<BatchResult> = vec![];
+    let mut resolved: Vec<Option<(String, At)>> = vec![];
+    for item in &body.items {
+        let mut result = BatchResult {
+            market: item.market.clone(),
+            date: item.date.clone(),
+            ..Default::default()
+        };
+        let at = parse_at(Some(item.date.as_str())).and_then(|at| match &item.currency {
+            Some(c) if !state.currencies.contains(&c.to_lowercase()) => {
+                Err(format!("unknown currency {}", c))
+            }
+            _ => Ok(at),
+        });
+        let market = resolve::market(&state.markets, &state.aliases, &item.market);
+        match (market, at) {
+            (Ok(m), Ok(at)) => {
+                result.market = m.name.clone();
+                resolved.push(Some((m.name, at)));
+            }
+            (Err(e), _) => {
+                result.error = Some(ApiError::from(e).into());
+                resolved.push(None);
+            }
+            (_, Err(e)) => {
+                result.error = Some(ApiError::input(&e).into());
+                resolved.push(None);
+            }
+        }
+        results.push(result);
+    }
+
+    let mut timestamps: HashMap<String, Vec<DateTime<Utc>>> = HashMap::new();
+    for (market, at) in resolved.iter().flatten() {
+        if let At::Time(tm) = at {
+            // rows are stored at the start of the day
+            let day = tm.date().and_hms(0, 0, 0);
+            timestamps.entry(market.clone()).or_default().push(day);
+        }
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    let mut stored: HashMap<String, BTreeMap<String, BTreeMap<String, f64>>> = HashMap::new();
+    for (market, tms) in timestamps {
+        let prices = db::get_prices_many(&mut conn, &tms, &market, &state.currencies).await?;
+        stored.insert(market, prices);
+    }
+    let current: BTreeMap<String, BTreeMap<String, f64>> =
+        if resolved.iter().flatten().any(|(_, at)| *at == At::Now) {
+            let val = fetch::current(&state.markets, &state.currencies, &Fields::default());
+            serde_json::from_str(&val).unwrap_or_default()
+        } else {
+            BTreeMap::new()
+        };
+
+    for (i, item) in body.items.iter().enumerate() {
+        let (market, at) = match &resolved[i] {
+            Some(x) => x,
+            None => continue,
+        };
+        let prices = match at {
+            At::Now => current.get(market),
+            At::Time(tm) => stored
+                .get(market)
+                .and_then(|x| x.get(&tm.format("%Y-%m-%d").to_string())),
+        };
+        let mut prices = match prices {
+            Some(x) => x.clone(),
+            None => {
+                let msg = format!("no prices of {} at {}", market, item.date);
+                results[i].error = Some(ApiError::not_found("no_prices", &msg).into());
+                continue;
+            }
+        };
+        if let Some(c) = &item.currency {
+            let c = c.to_lowercase();
+            prices.retain(|k, _| *k == c);
+        }
+        results[i].prices = Some(prices);
+    }
+
+    let mut res = Response::new(200);
+    res.set_body(serde_json::to_string(&BatchResponse { items: results })?);
+    Ok(res)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StatsQuery {
+    from: Option<String>,
+    to: Option<String>,
+    currency: Option<String>,
+}
+
+/// statistics of the stored daily prices, last year by default
+pub async fn stats(req: Request<State>) -> Result {
+    if let Err(e) = utc_only(&req) {
+        return Err(e.into());
+    }
+    let state = req.state();
+    let market = match resolve::market(&state.markets, &state.aliases, req.param("market")?) {
+        Ok(x) => x.name,
+        Err(e) => return resolve_error(e),
+    };
+    let query: StatsQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return input_error(&e.to_string()),
+    };
+    let tm_to = match &query.to {
+        Some(x) => match day_start(x) {
+            Ok(x) => x,
+            Err(e) => return input_error(&format!("to error: {}", e)),
+        },
+        None => Utc::today().and_hms(0, 0, 0),
+    };
+    let tm_from = match &query.from {
+        Some(x) => match day_start(x) {
+            Ok(x) => x,
+            Err(e) => return input_error(&format!("from error: {}", e)),
+        },
+        None => tm_to - chrono::Duration::days(365),
+    };
+    let currency = match query.currency {
+        Some(x) => x.to_lowercase(),
+        None => state.currencies.iter().next().cloned().unwrap_or_default(),
+    };
+    let currencies = match state.currencies.select(&currency) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+
+    let mut conn = state.db_pool.acquire().await?;
+    let rows = db::get_prices_period(
+        &mut conn,
+        tm_from,
+        tm_to,
+        &market,
+        &currencies,
+        &Fields::default(),
+    )
+    .await;
+    // gaps in the history are stored with negative prices
+    let series: Vec<(String, f64)> = rows
+        .into_iter()
+        .filter_map(|(ymd, prices)| match prices.get(&currency) {
+            Some(x) if *x > 0.0 => Some((ymd, *x)),
+            _ => None,
+        })
+        .collect();
+    let result = match crate::stats::compute(&series) {
+        Some(x) => x,
+        None => return input_error("no prices for the period"),
+    };
     let mut res = Response::new(200);
     res.set_body(serde_json::to_string(&result)?);
     Ok(res)
 }
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+pub struct MarketInfo {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// earliest date from the configuration
+    pub configured_earliest: Option<String>,
+    /// earliest date discovered from the provider
+    pub discovered_earliest: Option<String>,
+    pub first: Option<String>,
+    pub last: Option<String>,
+    pub rows: i64,
+    pub gaps: i64,
+    pub indexed_at: Option<String>,
+}
+
+/// configured markets with the coverage of their stored history
+pub async fn markets(req: Request<State>) -> Result {
+    let state = req.state();
+    let ymd = |dt: DateTime<Utc>| dt.format("%Y-%m-%d").to_string();
+    let mut conn = state.db_pool.acquire().await?;
+    let mut out: Vec<MarketInfo> = vec![];
+    for market in state.markets.iter() {
+        let coverage = db::get_coverage(&mut conn, &market.name, &state.currencies).await?;
+        out.push(MarketInfo {
+            name: market.name.clone(),
+            chain: market.contract.as_ref().map(|c| c.chain.clone()),
+            address: market.contract.as_ref().map(|c| c.address.clone()),
+            configured_earliest: market.earliest.map(|x| x.to_string()),
+            discovered_earliest: coverage.earliest.map(|x| x.to_string()),
+            first: coverage.first.map(ymd),
+            last: coverage.last.map(ymd),
+            rows: coverage.rows,
+            gaps: coverage.gaps,
+            indexed_at: coverage.indexed_at.map(|x| x.to_rfc3339()),
+        });
+    }
+    let mut res = Response::new(200);
+    res.set_body(serde_json::to_string(&out)?);
+    Ok(res)
+}
+
+type Sample = (DateTime<Utc>, BTreeMap<String, f64>);
+
+/// Price of the instant: the closest sample or linear between the samples around
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Nearest,
+    Linear,
+}
+
+/// prices at the instant from the samples around it, gaps (non-positive prices) are skipped
+fn interpolate(
+    tm: DateTime<Utc>,
+    mode: Mode,
+    before: &Option<Sample>,
+    after: &Option<Sample>,
+) -> BTreeMap<String, f64> {
+    let valid = |s: &Sample| -> BTreeMap<String, f64> {
+        s.1.iter()
+            .filter(|(_, v)| **v > 0.0)
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    };
+    match (before, after) {
+        (Some(b), Some(a)) if a.0 > b.0 => {
+            let span = (a.0 - b.0).num_seconds() as f64;
+            let w = (tm - b.0).num_seconds() as f64 / span;
+            let (pb, pa) = (valid(b), valid(a));
+            let mut out = BTreeMap::new();
+            for (k, vb) in &pb {
+                let v = match (mode, pa.get(k)) {
+                    (Mode::Linear, Some(va)) => vb + (va - vb) * w,
+                    (Mode::Nearest, Some(va)) if w > 0.5 => *va,
+                    _ => *vb,
+                };
+                out.insert(k.clone(), v);
+            }
+            for (k, va) in pa {
+                out.entry(k).or_insert(va);
+            }
+            out
+        }
+        (Some(b), _) => valid(b),
+        (None, Some(a)) => valid(a),
+        (None, None) => BTreeMap::new(),
+    }
+}
+
+/// stored samples at or before and at or after the instant,
+/// current snapshot is the latest sample of the unfinished day
+async fn samples_around(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::Postgres>,
+    state: &State,
+    market: &str,
+    currencies: &Currencies,
+    tm: DateTime<Utc>,
+) -> anyhow::Result<(Option<Sample>, Option<Sample>)> {
+    let before = db::get_prices_at(conn, tm, market, currencies).await?;
+    let mut after = db::get_prices_after(conn, tm, market, currencies).await?;
+    let now = Utc::now();
+    if after.is_none() && tm.date() == now.date() {
+        if let Ok(current) = current_markets(state, &Fields::default()) {
+            if let Some(prices) = current.get(market) {
+                after = Some((now, select_columns(prices, currencies, &Fields::default())));
+            }
+        }
+    }
+    Ok((before, after))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InstantQuery {
+    mode: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+pub struct InstantResponse {
+    pub market: String,
+    pub ts: i64,
+    pub mode: Mode,
+    /// time of the sample at or before the instant
+    pub before: Option<String>,
+    /// time of the sample at or after the instant
+    pub after: Option<String>,
+    pub prices: BTreeMap<String, f64>,
+}
+
+/// price of the market at the unix timestamp
+pub async fn history_ts(req: Request<State>) -> Result {
+    let state = req.state();
+    let market = match resolve::market(&state.markets, &state.aliases, req.param("market")?) {
+        Ok(x) => x.name,
+        Err(e) => return resolve_error(e),
+    };
+    let currencies = match query_currencies(&req) {
+        Ok(x) => x,
+        Err(e) => return input_error(&e),
+    };
+    let query: InstantQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return input_error(&e.to_string()),
+    };
+    let mode = match query.mode.as_deref() {
+        None | Some("linear") => Mode::Linear,
+        Some("nearest") => Mode::Nearest,
+        Some(x) => return input_error(&format!("unknown mode {}", x)),
+    };
+    let unix: i64 = match req.param("unix")?.parse() {
+        Ok(x) => x,
+        Err(e) => return input_error(&format!("timestamp: {}", e)),
+    };
+    let tm = match Utc.timestamp_opt(unix, 0).single() {
+        Some(x) => x,
+        None => return input_error(&format!("timestamp {} is out of range", unix)),
+    };
+    let now = Utc::now();
+    if tm > now {
+        return input_error("no prices for future");
+    }
+    info!("market={} ts={} mode={:?}", market, tm, mode);
+
+    let mut conn = state.db_pool.acquire().await?;
+    let (before, after) = samples_around(&mut conn, state, &market, &currencies, tm).await?;
+    if before.is_none() && after.is_none() {
+        return input_error(&format!("no prices for {} at {}", market, tm));
+    }
+
+    let response = InstantResponse {
+        market: market.clone(),
+        ts: unix,
+        mode,
+        before: before.as_ref().map(|x| x.0.to_rfc3339()),
+        after: after.as_ref().map(|x| x.0.to_rfc3339()),
+        prices: interpolate(tm, mode, &before, &after),
+    };
+    let mut res = Response::new(200);
+    res.set_body(serde_json::to_string(&response)?);
+    Ok(res)
+}
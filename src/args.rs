@@ -1,5 +1,6 @@
-use crate::{Currencies, Markets};
+use crate::{Aliases, AuthMode, Currencies, Markets, Origins, Proxies};
 use structopt::StructOpt;
+use tide::http::headers::HeaderValue;
 use tracing_subscriber::prelude::*;
 
 #[derive(Debug, StructOpt, Clone)]
@@ -8,7 +9,7 @@ use tracing_subscriber::prelude::*;
     about = "API to serve fiat prics of cryptocurrencies. Caches Coingecko so far"
 )]
 pub struct Args {
-    /// whether to index missing history
+    /// whether to index missing history, also hourly while the server runs
     #[structopt(short, long, default_value = "1")]
     pub index: u32,
     /// whether to start HTTP API server
@@ -16,6 +17,9 @@ pub struct Args {
     pub server: u32,
     #[structopt(long, default_value = "ethereum,bitcoin", env = "MARKETS")]
     pub markets: Markets,
+    /// short names of the markets, accepted by the API instead of market id
+    #[structopt(long, default_value = "btc=bitcoin,eth=ethereum", env = "ALIASES")]
+    pub aliases: Aliases,
     #[structopt(
         long,
         default_value = "eur,usd,rub,cny,cad,jpy,gbp",
@@ -31,8 +35,47 @@ pub struct Args {
     pub database_url: String,
     #[structopt(long, default_value = "5", env = "DATABASE_MAX_CONN")]
     pub database_conn: u32,
+    /// longest period in days that is served in one JSON response
+    #[structopt(long, default_value = "366", env = "MAX_PERIOD_DAYS")]
+    pub max_period_days: i64,
+    /// seconds between the checks of the current prices for the live stream
+    #[structopt(long, default_value = "15", env = "STREAM_INTERVAL")]
+    pub stream_interval: u64,
     #[structopt(short, long, default_value = "0.0.0.0:8080", env = "LISTEN")]
     pub addr: String,
+    /// whether the API keys are checked: off, optional or required
+    #[structopt(long, default_value = "off", env = "AUTH")]
+    pub auth: AuthMode,
+    /// file with the API keys, one `<key> <name> [rate limit]` per line.
+    /// keys are read from `api_keys` table by their SHA-256 when it is not set
+    #[structopt(long, env = "API_KEYS_FILE")]
+    pub api_keys_file: Option<String>,
+    /// requests per minute of the keys without own limit and of the clients without the key
+    #[structopt(long, default_value = "60", env = "RATE_LIMIT")]
+    pub rate_limit: u32,
+    /// comma separated addresses of the reverse proxies, clients without the key
+    /// are limited by `X-Forwarded-For` only when the request comes from them
+    #[structopt(long, default_value = "", env = "TRUSTED_PROXIES")]
+    pub trusted_proxies: Proxies,
+    /// smallest response body in bytes that is compressed
+    #[structopt(long, default_value = "1024", env = "COMPRESS_MIN_BYTES")]
+    pub compress_min_bytes: usize,
+    /// comma separated origins allowed to call the API from the browser,
+    /// `*` for any origin, CORS is disabled when empty
+    #[structopt(long, default_value = "", env = "CORS_ORIGINS")]
+    pub cors_origins: Origins,
+    #[structopt(long, default_value = "GET, POST, OPTIONS", env = "CORS_METHODS")]
+    pub cors_methods: HeaderValue,
+    /// request headers allowed from the browser, a wildcard doesn't cover `Authorization`
+    #[structopt(
+        long,
+        default_value = "authorization, x-api-key, content-type",
+        env = "CORS_HEADERS"
+    )]
+    pub cors_headers: HeaderValue,
+    /// seconds the browser may cache the preflight response
+    #[structopt(long, default_value = "86400", env = "CORS_MAX_AGE")]
+    pub cors_max_age: HeaderValue,
 }
 
 pub fn parse() -> anyhow::Result<Args> {
@@ -0,0 +1,358 @@
+use crate::error::ApiError;
+use crate::{db, AuthMode, Proxies};
+use anyhow::{bail, Result};
+use async_std::task;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use sqlx::pool::PoolConnection;
+use sqlx::Postgres;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use tracing::{info, warn};
+
+/// routes for monitoring and discovery, served without the key
+pub const PUBLIC_PATHS: &[&str] = &["/metrics", "/api/health", "/api/openapi.json"];
+
+/// Client of the API identified by the key
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    /// requests per minute
+    pub rate_limit: u32,
+}
+
+/// clients by the hash of their key, see `hash_key`
+pub type Keys = HashMap<String, ApiKey>;
+
+/// hex of SHA-256 of the key, as the keys are stored and looked up
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// keys from the file, one per line as `<key> <name> [requests per minute]`,
+/// `#` starts a comment. names are unique, as the usage is counted by them
+pub fn load_file(path: &str, default_rate: u32) -> Result<Keys> {
+    let text = std::fs::read_to_string(path)?;
+    let mut keys = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            bail!("{}:{}: expected <key> <name> [rate limit]", path, n + 1);
+        }
+        let rate_limit = match parts.get(2) {
+            Some(x) => match x.parse::<u32>() {
+                Ok(x) if x > 0 => x,
+                _ => bail!("{}:{}: invalid rate limit {}", path, n + 1, x),
+            },
+            None => default_rate,
+        };
+        if keys.values().any(|x: &ApiKey| x.name == parts[1]) {
+            bail!("{}:{}: duplicate name {}", path, n + 1, parts[1]);
+        }
+        let key = ApiKey {
+            name: parts[1].to_owned(),
+            rate_limit,
+        };
+        if keys.insert(hash_key(parts[0]), key).is_some() {
+            bail!("{}:{}: duplicate key", path, n + 1);
+        }
+    }
+    Ok(keys)
+}
+
+/// enabled keys from the database
+pub async fn load_db(conn: &mut PoolConnection<Postgres>, default_rate: u32) -> Result<Keys> {
+    let rows = db::get_keys(conn).await?;
+    let keys = rows
+        .into_iter()
+        .map(|(key_hash, name, rate_limit)| {
+            let rate_limit = match rate_limit {
+                Some(x) if x > 0 => x as u32,
+                _ => default_rate,
+            };
+            (key_hash, ApiKey { name, rate_limit })
+        })
+        .collect();
+    Ok(keys)
+}
+
+/// Token bucket holding up to a minute of requests, refilled continuously
+#[derive(Debug, Clone)]
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, now: Instant) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// takes the token of the request, or returns the seconds until it is available
+    fn take(&mut self, now: Instant) -> std::result::Result<(), f64> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - self.tokens) * 60.0 / self.capacity)
+        }
+    }
+
+    /// seconds until the bucket is full again
+    fn reset(&self) -> f64 {
+        (self.capacity - self.tokens) * 60.0 / self.capacity
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct KeyQuery {
+    api_key: Option<String>,
+}
+
+/// hash of the key from `X-API-Key` or `Authorization: Bearer` header,
+/// or from `api_key=` parameter
+fn request_key<State>(req: &Request<State>) -> Option<String> {
+    if let Some(x) = req.header("x-api-key") {
+        return Some(hash_key(x.as_str().trim()));
+    }
+    if let Some(x) = req.header("authorization") {
+        if let Some(token) = x.as_str().strip_prefix("Bearer ") {
+            return Some(hash_key(token.trim()));
+        }
+    }
+    let query: KeyQuery = req.query().ok()?;
+    query.api_key.map(|x| hash_key(&x))
+}
+
+/// address of the client: the peer of the connection, or the last address of
+/// `X-Forwarded-For` that is not one of the trusted proxies when the peer is one of them
+fn client_ip<State>(req: &Request<State>, proxies: &Proxies) -> Option<IpAddr> {
+    let peer = req.peer_addr()?;
+    let peer = match peer.parse::<SocketAddr>() {
+        Ok(x) => x.ip(),
+        Err(_) => peer.parse::<IpAddr>().ok()?,
+    };
+    if !proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded = match req.header("x-forwarded-for") {
+        Some(x) => x
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<&str>>()
+            .join(","),
+        None => return Some(peer),
+    };
+    // the proxies append the address they got the request from, earlier ones are up to the client
+    let mut ip = peer;
+    for x in forwarded.rsplit(',').map(|x| x.trim()) {
+        match x.parse::<IpAddr>() {
+            Ok(x) => ip = x,
+            Err(_) => break,
+        }
+        if !proxies.contains(&ip) {
+            break;
+        }
+    }
+    Some(ip)
+}
+
+/// bucket of the client without the key, IPv6 clients are limited by their /64 network
+fn address_client(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V6(x)) => {
+            let s = x.segments();
+            // IPv4 clients of the dual stack socket
+            if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                return format!(
+                    "ip:{}.{}.{}.{}",
+                    s[6] >> 8,
+                    s[6] & 0xff,
+                    s[7] >> 8,
+                    s[7] & 0xff
+                );
+            }
+            format!("ip:{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+        Some(x) => format!("ip:{}", x),
+        None => "ip:unknown".to_owned(),
+    }
+}
+
+/// Authenticates the requests with the API keys and limits their rate per key.
+/// Clients without the key are limited per address when the key is optional
+#[derive(Clone)]
+pub struct AuthMiddleware {
+    mode: AuthMode,
+    default_rate: u32,
+    keys: Arc<RwLock<Keys>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    /// requests per key name since the last flush
+    usage: Arc<Mutex<HashMap<String, i64>>>,
+    proxies: Proxies,
+}
+
+impl AuthMiddleware {
+    pub fn new(mode: AuthMode, default_rate: u32, keys: Keys, proxies: Proxies) -> Self {
+        Self {
+            mode,
+            default_rate,
+            keys: Arc::new(RwLock::new(keys)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            proxies,
+        }
+    }
+
+    pub fn set_keys(&self, keys: Keys) {
+        *self.keys.write().unwrap() = keys;
+    }
+
+    /// usage counters collected since the previous call
+    pub fn take_usage(&self) -> HashMap<String, i64> {
+        std::mem::take(&mut *self.usage.lock().unwrap())
+    }
+
+    /// forgets the clients that are back to the full bucket
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|_, b| {
+            b.refill(now);
+            b.tokens < b.capacity
+        });
+    }
+}
+
+fn rate_headers(res: &mut Response, limit: u32, bucket: &Bucket) {
+    res.insert_header("x-ratelimit-limit", limit.to_string());
+    res.insert_header(
+        "x-ratelimit-remaining",
+        (bucket.tokens.floor() as u32).to_string(),
+    );
+    res.insert_header(
+        "x-ratelimit-reset",
+        (bucket.reset().ceil() as u64).to_string(),
+    );
+}
+
+fn reject(status: StatusCode, code: &'static str, message: &str) -> Response {
+    let mut res = Response::new(status);
+    res.set_error(ApiError::new(status, code, message));
+    res
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuthMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if self.mode == AuthMode::Off || PUBLIC_PATHS.contains(&req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+        let (client, limit) = match request_key(&req) {
+            Some(key) => match self.keys.read().unwrap().get(&key) {
+                Some(x) => (x.name.clone(), x.rate_limit),
+                None => {
+                    let msg = "unknown API key";
+                    return Ok(reject(StatusCode::Unauthorized, "invalid_api_key", msg));
+                }
+            },
+            None if self.mode == AuthMode::Required => {
+                let msg = "API key is required";
+                return Ok(reject(StatusCode::Unauthorized, "missing_api_key", msg));
+            }
+            None => (
+                address_client(client_ip(&req, &self.proxies)),
+                self.default_rate,
+            ),
+        };
+
+        let now = Instant::now();
+        let (taken, bucket) = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = buckets
+                .entry(client.clone())
+                .or_insert_with(|| Bucket::new(limit as f64, now));
+            // the limit of the key could be changed since the last request
+            bucket.capacity = limit as f64;
+            (bucket.take(now), bucket.clone())
+        };
+        if let Err(wait) = taken {
+            let msg = format!("rate limit of {} requests per minute is exceeded", limit);
+            let mut res = reject(StatusCode::TooManyRequests, "rate_limited", &msg);
+            rate_headers(&mut res, limit, &bucket);
+            res.insert_header("retry-after", (wait.ceil() as u64).to_string());
+            return Ok(res);
+        }
+        if !client.starts_with("ip:") {
+            *self.usage.lock().unwrap().entry(client).or_insert(0) += 1;
+        }
+
+        let mut res = next.run(req).await;
+        rate_headers(&mut res, limit, &bucket);
+        Ok(res)
+    }
+}
+
+/// every minute saves the usage counters, drops idle clients
+/// and reloads the keys when they are kept in the database
+pub async fn maintain(auth: AuthMiddleware, pool: sqlx::Pool<Postgres>, reload: bool) {
+    loop {
+        task::sleep(Duration::from_secs(60)).await;
+        auth.prune();
+        let mut conn = match pool.acquire().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("api keys maintenance: {}", e);
+                continue;
+            }
+        };
+        let today = Utc::today().naive_utc();
+        for (name, requests) in auth.take_usage() {
+            if let Err(e) = db::add_usage(&mut conn, &name, today, requests).await {
+                warn!("usage of {} is lost: {}", name, e);
+            }
+        }
+        if reload {
+            match load_db(&mut conn, auth.default_rate).await {
+                Ok(keys) => auth.set_keys(keys),
+                Err(e) => warn!("api keys reload: {}", e),
+            }
+        }
+    }
+}
+
+/// keys from the file when it is configured, from the database otherwise
+pub async fn load(
+    conn: &mut PoolConnection<Postgres>,
+    file: &Option<String>,
+    default_rate: u32,
+) -> Result<Keys> {
+    db::create_keys_tables(conn).await?;
+    let keys = match file {
+        Some(path) => load_file(path, default_rate)?,
+        None => load_db(conn, default_rate).await?,
+    };
+    info!("{} api keys loaded", keys.len());
+    Ok(keys)
+}
@@ -0,0 +1,104 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use tide::http::Method;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+/// How long the response may be reused, set by the handlers as the response extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// prices of the finished days never change
+    Immutable,
+    /// prices that are refreshed, reusable for the seconds left until the refresh
+    MaxAge(i64),
+}
+
+impl Policy {
+    fn header(&self, private: bool) -> String {
+        let scope = if private { "private" } else { "public" };
+        match self {
+            Policy::Immutable => format!("{}, max-age=31536000, immutable", scope),
+            Policy::MaxAge(secs) => format!("{}, max-age={}", scope, secs.max(&0)),
+        }
+    }
+}
+
+fn etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// whether `If-None-Match` header lists the tag, weak tags included
+fn matches(if_none_match: &str, tag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == tag)
+}
+
+/// Adds `Cache-Control` and `ETag` to the successful responses having the caching `Policy`,
+/// answers `304 Not Modified` when the client already has the same body.
+/// Streamed bodies only get `Cache-Control`, their length and tag are unknown upfront
+#[derive(Debug, Default, Clone)]
+pub struct CacheMiddleware {
+    /// request headers the responses depend on besides the URL
+    vary: Vec<&'static str>,
+    /// responses are only for the client that sent the API key, not for shared caches
+    private: bool,
+}
+
+impl CacheMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// responses also differ by the request header, listed in `Vary` of each of them
+    pub fn vary(mut self, header: &'static str) -> Self {
+        self.vary.push(header);
+        self
+    }
+
+    /// responses are cached only by the client, as the access to them is checked
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CacheMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let is_get = req.method() == Method::Get;
+        let if_none_match = req.header("if-none-match").map(|x| x.as_str().to_owned());
+        let mut res = next.run(req).await;
+        for header in &self.vary {
+            res.append_header("vary", *header);
+        }
+        let policy = match res.ext::<Policy>() {
+            Some(x) => *x,
+            None => return Ok(res),
+        };
+        if !is_get || res.status() != StatusCode::Ok {
+            return Ok(res);
+        }
+        res.insert_header("cache-control", policy.header(self.private));
+        if res.len().is_none() {
+            return Ok(res);
+        }
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+        let tag = etag(&bytes);
+        res.insert_header("etag", tag.as_str());
+        if let Some(x) = if_none_match {
+            if matches(&x, &tag) {
+                res.set_status(StatusCode::NotModified);
+                return Ok(res);
+            }
+        }
+        let mut body = Body::from_bytes(bytes);
+        body.set_mime(mime);
+        res.set_body(body);
+        Ok(res)
+    }
+}
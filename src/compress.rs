@@ -0,0 +1,112 @@
+use async_compression::futures::bufread::{BrotliEncoder, GzipEncoder};
+use async_compression::Level;
+use async_std::io::BufReader;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+/// brotli quality of the streamed bodies, the default 11 is too slow for the long periods
+const BROTLI_QUALITY: u32 = 4;
+
+/// Content coding of the response
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// preferred coding from `Accept-Encoding`, brotli wins the ties
+fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(|x| x.trim());
+        let encoding = match parts.next().unwrap_or("").to_lowercase().as_str() {
+            "br" => Encoding::Brotli,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+        let q = parts
+            .filter_map(|x| x.strip_prefix("q="))
+            .filter_map(|x| x.parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        if q <= 0.0 {
+            continue;
+        }
+        best = match best {
+            Some((_, x)) if x > q => best,
+            Some((Encoding::Brotli, x)) if x == q => best,
+            _ => Some((encoding, q)),
+        };
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses the response bodies negotiated through `Accept-Encoding`.
+/// Bodies shorter than the threshold are sent as is, streamed bodies are always compressed
+#[derive(Debug, Clone)]
+pub struct CompressMiddleware {
+    min_bytes: usize,
+}
+
+impl CompressMiddleware {
+    pub fn new(min_bytes: usize) -> Self {
+        Self { min_bytes }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CompressMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let encoding = match req.header("accept-encoding") {
+            Some(x) => negotiate(x.as_str()),
+            None => None,
+        };
+        let mut res = next.run(req).await;
+        res.append_header("vary", "accept-encoding");
+        let encoding = match encoding {
+            Some(x) => x,
+            None => return Ok(res),
+        };
+        if res.status() == StatusCode::NotModified || res.header("content-encoding").is_some() {
+            return Ok(res);
+        }
+        // events should reach the client as soon as they are sent
+        if let Some(mime) = res.content_type() {
+            if mime.essence() == "text/event-stream" {
+                return Ok(res);
+            }
+        }
+        if let Some(len) = res.len() {
+            if len < self.min_bytes {
+                return Ok(res);
+            }
+        }
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let mut body = match encoding {
+            Encoding::Brotli => {
+                let encoder = BrotliEncoder::with_quality(body, Level::Precise(BROTLI_QUALITY));
+                Body::from_reader(BufReader::new(encoder), None)
+            }
+            Encoding::Gzip => Body::from_reader(BufReader::new(GzipEncoder::new(body)), None),
+        };
+        body.set_mime(mime);
+        res.set_body(body);
+        res.insert_header("content-encoding", encoding.name());
+        // compressed bytes differ from the tagged ones, the content is the same
+        if let Some(tag) = res.header("etag").map(|x| x.as_str().to_owned()) {
+            if !tag.starts_with("W/") {
+                res.insert_header("etag", format!("W/{}", tag));
+            }
+        }
+        Ok(res)
+    }
+}
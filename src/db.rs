@@ -1,7 +1,10 @@
-use crate::Currencies;
+use crate::{Currencies, Field, Fields};
 use anyhow::Result;
+use async_std::channel::{self, Receiver};
+use async_std::prelude::*;
+use async_std::task;
 use bigdecimal::BigDecimal;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use sqlx::pool::PoolConnection;
 use sqlx::{Postgres, Row};
 use std::collections::{BTreeMap, HashMap};
@@ -18,7 +21,7 @@ pub async fn create_table(
     let tbl = get_table_name(market);
     let mut currency_fields: Vec<String> = vec![];
     for currency in currencies.iter() {
-        currency_fields.push(format!("{} numeric(20,10) not null", currency))
+        currency_fields.push(format!("{} numeric(20,10)", currency))
     }
     let sql = format!(
         "create table if not exists {} (
@@ -26,36 +29,392 @@ pub async fn create_table(
         tbl,
         currency_fields.join(", ")
     );
-    if let Err(e) = sqlx::query(&sql).execute(conn).await {
+    if let Err(e) = sqlx::query(&sql).execute(&mut *conn).await {
         panic!("sql create error {}", e);
     };
+    // market cap and volume were added later and are missing in older rows,
+    // prices were required before the provider nulls were kept as is
+    for currency in currencies.iter() {
+        let sql = format!(
+            "alter table {} alter column {} drop not null",
+            tbl, currency
+        );
+        if let Err(e) = sqlx::query(&sql).execute(&mut *conn).await {
+            panic!("sql alter error {}", e);
+        };
+        for field in &[Field::MarketCap, Field::Volume] {
+            let sql = format!(
+                "alter table {} add column if not exists {} numeric",
+                tbl,
+                field.column(currency)
+            );
+            if let Err(e) = sqlx::query(&sql).execute(&mut *conn).await {
+                panic!("sql alter error {}", e);
+            };
+        }
+    }
+    Ok(())
+}
+
+/// prices of the market at the start of each hour, kept for the recent days only
+pub fn get_hourly_table_name(market: &str) -> String {
+    format!("hourly_{}", market)
+}
+
+pub async fn create_hourly_table(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+    currencies: &Currencies,
+) -> Result<()> {
+    let tbl = get_hourly_table_name(market);
+    let currency_fields: Vec<String> = currencies
+        .iter()
+        .map(|c| format!("{} numeric(20,10)", c))
+        .collect();
+    let sql = format!(
+        "create table if not exists {} (
+        ts timestamptz not null, {}, primary key (ts))",
+        tbl,
+        currency_fields.join(", ")
+    );
+    if let Err(e) = sqlx::query(&sql).execute(&mut *conn).await {
+        panic!("sql create error {}", e);
+    };
+    Ok(())
+}
+
+fn get_values(
+    row: &sqlx::postgres::PgRow,
+    currencies: &Currencies,
+    fields: &Fields,
+) -> BTreeMap<String, f64> {
+    let mut m = BTreeMap::new();
+    for column in fields.columns(currencies) {
+        let num: Option<BigDecimal> = row.get(column.as_str());
+        if let Some(num) = num {
+            let val: f64 = num.to_string().parse().unwrap_or(0.0);
+            m.insert(column, val);
+        }
+    }
+    m
+}
+
+/// table with the details of each market, discovered while indexing
+pub async fn create_markets_table(conn: &mut PoolConnection<Postgres>) -> Result<()> {
+    let sql = "create table if not exists markets (
+        name text not null, earliest date, primary key (name))";
+    if let Err(e) = sqlx::query(sql).execute(&mut *conn).await {
+        panic!("sql create error {}", e);
+    };
+    let sql = "alter table markets add column if not exists indexed_at timestamptz";
+    if let Err(e) = sqlx::query(sql).execute(&mut *conn).await {
+        panic!("sql alter error {}", e);
+    };
+    // market cap and volume of the rows indexed before they were stored
+    let sql = "alter table markets add column if not exists
+        fields_backfilled boolean not null default false";
+    if let Err(e) = sqlx::query(sql).execute(&mut *conn).await {
+        panic!("sql alter error {}", e);
+    };
+    Ok(())
+}
+
+pub async fn is_backfilled(conn: &mut PoolConnection<Postgres>, market: &str) -> Result<bool> {
+    let sql = "SELECT fields_backfilled FROM markets WHERE name = $1";
+    let done: Option<bool> = sqlx::query_scalar(sql)
+        .bind(market)
+        .fetch_optional(conn)
+        .await?;
+    Ok(done.unwrap_or(false))
+}
+
+pub async fn set_backfilled(conn: &mut PoolConnection<Postgres>, market: &str) -> Result<()> {
+    let sql = "INSERT INTO markets (name, fields_backfilled) VALUES ($1, true)
+        ON CONFLICT (name) DO UPDATE SET fields_backfilled = true";
+    sqlx::query(sql).bind(market).execute(conn).await?;
+    Ok(())
+}
+
+/// days with prices, but without market cap and volume of any currency
+pub async fn get_missing_fields(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+    currencies: &Currencies,
+) -> Result<Vec<DateTime<Utc>>> {
+    let mut missing: Vec<String> = vec![];
+    for currency in currencies.iter() {
+        for field in &[Field::MarketCap, Field::Volume] {
+            missing.push(format!("{} IS NULL", field.column(currency)));
+        }
+    }
+    let currency = currencies.iter().next().cloned().unwrap_or_default();
+    let sql = format!(
+        "SELECT ts FROM {} WHERE {} > 0 AND {} ORDER BY ts",
+        get_table_name(market),
+        currency,
+        missing.join(" AND "),
+    );
+    let rows: Vec<DateTime<Utc>> = sqlx::query_scalar(&sql).fetch_all(conn).await?;
+    Ok(rows)
+}
+
+/// sets the values of the stored day, keyed by database column
+pub async fn update_fields(
+    conn: &mut PoolConnection<Postgres>,
+    timestamp: DateTime<Utc>,
+    market: &str,
+    values: &HashMap<String, f64>,
+) -> Result<()> {
+    let sets: Vec<String> = values
+        .iter()
+        .map(|(column, value)| format!("{} = {}", column, value))
+        .collect();
+    let sql = format!(
+        "UPDATE {} SET {} WHERE ts = $1",
+        get_table_name(market),
+        sets.join(", ")
+    );
+    sqlx::query(&sql).bind(timestamp).execute(conn).await?;
+    Ok(())
+}
+
+/// saves the time of the last completed indexing of the market
+pub async fn set_indexed(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+    indexed_at: DateTime<Utc>,
+) -> Result<()> {
+    let sql = "INSERT INTO markets (name, indexed_at) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET indexed_at = EXCLUDED.indexed_at";
+    sqlx::query(sql)
+        .bind(market)
+        .bind(indexed_at)
+        .execute(conn)
+        .await?;
     Ok(())
 }
 
+/// API keys and their daily usage counters. keys are stored as the hex of their SHA-256,
+/// i.e. `encode(sha256('<key>'), 'hex')`, names are unique as the usage is counted by them
+pub async fn create_keys_tables(conn: &mut PoolConnection<Postgres>) -> Result<()> {
+    let sql = "create table if not exists api_keys (
+        key_hash text not null, name text not null unique, rate_limit integer,
+        disabled boolean not null default false, primary key (key_hash))";
+    if let Err(e) = sqlx::query(sql).execute(&mut *conn).await {
+        panic!("sql create error {}", e);
+    };
+    // the keys were stored as they are before
+    let sql = "SELECT count(*) FROM information_schema.columns
+        WHERE table_name = 'api_keys' AND column_name = 'key'";
+    let plain: i64 = sqlx::query_scalar(sql).fetch_one(&mut *conn).await?;
+    if plain > 0 {
+        for sql in &[
+            "alter table api_keys rename column key to key_hash",
+            "update api_keys set key_hash = encode(sha256(convert_to(key_hash, 'UTF8')), 'hex')",
+            "create unique index if not exists api_keys_name on api_keys (name)",
+        ] {
+            if let Err(e) = sqlx::query(sql).execute(&mut *conn).await {
+                panic!("sql alter error {}", e);
+            };
+        }
+    }
+    let sql = "create table if not exists api_key_usage (
+        name text not null, day date not null, requests bigint not null default 0,
+        primary key (name, day))";
+    if let Err(e) = sqlx::query(sql).execute(&mut *conn).await {
+        panic!("sql create error {}", e);
+    };
+    Ok(())
+}
+
+/// enabled API keys: key hash, name and the requests per minute when it differs from the default
+pub async fn get_keys(
+    conn: &mut PoolConnection<Postgres>,
+) -> Result<Vec<(String, String, Option<i32>)>> {
+    let sql = "SELECT key_hash, name, rate_limit FROM api_keys WHERE NOT disabled";
+    let rows = sqlx::query_as(sql).fetch_all(conn).await?;
+    Ok(rows)
+}
+
+/// adds the requests made with the key during the day
+pub async fn add_usage(
+    conn: &mut PoolConnection<Postgres>,
+    name: &str,
+    day: NaiveDate,
+    requests: i64,
+) -> Result<()> {
+    let sql = "INSERT INTO api_key_usage (name, day, requests) VALUES ($1, $2, $3)
+        ON CONFLICT (name, day) DO UPDATE SET requests = api_key_usage.requests + EXCLUDED.requests";
+    sqlx::query(sql)
+        .bind(name)
+        .bind(day)
+        .bind(requests)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Stored history of the market
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    pub earliest: Option<NaiveDate>,
+    pub indexed_at: Option<DateTime<Utc>>,
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+    pub rows: i64,
+    /// days without prices between the first and the last row
+    pub gaps: i64,
+}
+
+pub async fn get_coverage(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+    currencies: &Currencies,
+) -> Result<Coverage> {
+    let mut out = Coverage::default();
+    let sql = "SELECT earliest, indexed_at FROM markets WHERE name = $1";
+    if let Some(row) = sqlx::query(sql)
+        .bind(market)
+        .fetch_optional(&mut *conn)
+        .await?
+    {
+        out.earliest = row.get("earliest");
+        out.indexed_at = row.get("indexed_at");
+    }
+    // gaps are either missing days or rows stored with negative prices
+    let currency = currencies.iter().next().cloned().unwrap_or_default();
+    let sql = format!(
+        "SELECT min(ts) AS first, max(ts) AS last, count(*) AS rows,
+        count(*) FILTER (WHERE {c} IS NULL OR {c} < 0) AS empty FROM {}",
+        get_table_name(market),
+        c = currency,
+    );
+    let row = sqlx::query(&sql).fetch_one(&mut *conn).await?;
+    out.first = row.get("first");
+    out.last = row.get("last");
+    out.rows = row.get("rows");
+    let empty: i64 = row.get("empty");
+    if let (Some(first), Some(last)) = (out.first, out.last) {
+        let days = (last - first).num_days() + 1;
+        out.gaps = days - out.rows + empty;
+    }
+    Ok(out)
+}
+
+pub async fn get_earliest(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+) -> Result<Option<NaiveDate>> {
+    let sql = "SELECT earliest FROM markets WHERE name = $1";
+    let dt: Option<Option<NaiveDate>> = sqlx::query_scalar(sql)
+        .bind(market)
+        .fetch_optional(conn)
+        .await?;
+    Ok(dt.flatten())
+}
+
+pub async fn set_earliest(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+    earliest: NaiveDate,
+) -> Result<()> {
+    let sql = "INSERT INTO markets (name, earliest) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET earliest = EXCLUDED.earliest";
+    sqlx::query(sql)
+        .bind(market)
+        .bind(earliest)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// prices of the first stored day at or after the timestamp, with the time of the row
 pub async fn get_prices(
     conn: &mut PoolConnection<Postgres>,
     timestamp: DateTime<Utc>,
     market: &str,
     currencies: &Currencies,
-) -> BTreeMap<String, f64> {
+    fields: &Fields,
+) -> Option<(DateTime<Utc>, BTreeMap<String, f64>)> {
     let sql = format!(
-        "SELECT {} FROM {} WHERE ts >= $1 LIMIT 1",
-        currencies.as_vec().join(","),
+        "SELECT ts,{} FROM {} WHERE ts >= $1 ORDER BY ts LIMIT 1",
+        fields.columns(currencies).join(","),
         get_table_name(market),
     );
-    let row = match sqlx::query(&sql).bind(timestamp).fetch_one(conn).await {
+    let row = match sqlx::query(&sql).bind(timestamp).fetch_optional(conn).await {
         Ok(x) => x,
         Err(e) => {
             panic!("sql prices has error {}", e);
         }
     };
-    let mut m = BTreeMap::new();
-    for currency in currencies.iter() {
-        let num: BigDecimal = row.get(currency.as_str());
-        let val: f64 = num.to_string().parse().unwrap_or(0.0);
-        m.insert(currency.to_string(), val);
-    }
-    m
+    // no prices after the timestamp, i.e. market history is not indexed yet
+    row.map(|row| {
+        let ts: DateTime<Utc> = row.get("ts");
+        (ts, get_values(&row, currencies, fields))
+    })
+}
+
+/// number of stored days of the period, gaps included
+pub async fn count_prices(
+    conn: &mut PoolConnection<Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    market: &str,
+) -> Result<i64> {
+    let sql = format!(
+        "SELECT count(*) FROM {} WHERE ts >= $1 AND ts <= $2",
+        get_table_name(market),
+    );
+    let count: i64 = sqlx::query_scalar(&sql)
+        .bind(from)
+        .bind(to)
+        .fetch_one(conn)
+        .await?;
+    Ok(count)
+}
+
+/// latest stored prices at or before the timestamp, with the time of the row
+pub async fn get_prices_at(
+    conn: &mut PoolConnection<Postgres>,
+    timestamp: DateTime<Utc>,
+    market: &str,
+    currencies: &Currencies,
+) -> Result<Option<(DateTime<Utc>, BTreeMap<String, f64>)>> {
+    let sql = format!(
+        "SELECT ts,{} FROM {} WHERE ts <= $1 ORDER BY ts DESC LIMIT 1",
+        currencies.as_vec().join(","),
+        get_table_name(market),
+    );
+    let row = sqlx::query(&sql)
+        .bind(timestamp)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.map(|row| {
+        let ts: DateTime<Utc> = row.get("ts");
+        (ts, get_values(&row, currencies, &Fields::default()))
+    }))
+}
+
+/// earliest stored prices at or after the timestamp, with the time of the row
+pub async fn get_prices_after(
+    conn: &mut PoolConnection<Postgres>,
+    timestamp: DateTime<Utc>,
+    market: &str,
+    currencies: &Currencies,
+) -> Result<Option<(DateTime<Utc>, BTreeMap<String, f64>)>> {
+    let sql = format!(
+        "SELECT ts,{} FROM {} WHERE ts >= $1 ORDER BY ts LIMIT 1",
+        currencies.as_vec().join(","),
+        get_table_name(market),
+    );
+    let row = sqlx::query(&sql)
+        .bind(timestamp)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.map(|row| {
+        let ts: DateTime<Utc> = row.get("ts");
+        (ts, get_values(&row, currencies, &Fields::default()))
+    }))
 }
 
 pub async fn get_prices_period(
@@ -64,10 +423,11 @@ pub async fn get_prices_period(
     to: DateTime<Utc>,
     market: &str,
     currencies: &Currencies,
+    fields: &Fields,
 ) -> BTreeMap<String, BTreeMap<String, f64>> {
     let sql = format!(
         "SELECT ts,{} FROM {} WHERE ts >= $1 AND ts <= $2",
-        currencies.as_vec().join(","),
+        fields.columns(currencies).join(","),
         get_table_name(market),
     );
     let rows = sqlx::query(&sql)
@@ -80,15 +440,284 @@ pub async fn get_prices_period(
     for row in rows {
         let ts: DateTime<Utc> = row.get("ts");
         let ymd = ts.format("%Y-%m-%d").to_string();
+        out.insert(ymd, get_values(&row, currencies, fields));
+    }
+    out
+}
+
+pub type PriceRow = (String, BTreeMap<String, f64>);
+
+/// up to `limit` rows of the period, with the date of the next row when there are more
+pub async fn get_prices_page(
+    conn: &mut PoolConnection<Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    limit: i64,
+    market: &str,
+    currencies: &Currencies,
+    fields: &Fields,
+) -> Result<(BTreeMap<String, BTreeMap<String, f64>>, Option<String>)> {
+    let sql = format!(
+        "SELECT ts,{} FROM {} WHERE ts >= $1 AND ts <= $2 ORDER BY ts LIMIT $3",
+        fields.columns(currencies).join(","),
+        get_table_name(market),
+    );
+    let rows = sqlx::query(&sql)
+        .bind(from)
+        .bind(to)
+        .bind(limit + 1)
+        .fetch_all(conn)
+        .await?;
+    let mut out = BTreeMap::new();
+    let mut next = None;
+    for (i, row) in rows.iter().enumerate() {
+        let ts: DateTime<Utc> = row.get("ts");
+        let ymd = ts.format("%Y-%m-%d").to_string();
+        if i as i64 == limit {
+            next = Some(ymd);
+            break;
+        }
+        out.insert(ymd, get_values(row, currencies, fields));
+    }
+    Ok((out, next))
+}
+
+/// rows of the period in the order of dates, read from the database cursor
+/// in the background task with its own connection
+pub fn stream_prices_period(
+    pool: sqlx::Pool<Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    market: &str,
+    currencies: &Currencies,
+    fields: &Fields,
+) -> Receiver<Result<PriceRow>> {
+    let (tx, rx) = channel::bounded(64);
+    let sql = format!(
+        "SELECT ts,{} FROM {} WHERE ts >= $1 AND ts <= $2 ORDER BY ts",
+        fields.columns(currencies).join(","),
+        get_table_name(market),
+    );
+    let currencies = currencies.clone();
+    let fields = fields.clone();
+    task::spawn(async move {
+        let mut conn = match pool.acquire().await {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = tx.send(Err(e.into())).await;
+                return;
+            }
+        };
+        let mut rows = sqlx::query(&sql).bind(from).bind(to).fetch(&mut conn);
+        while let Some(row) = rows.next().await {
+            let item = row.map_err(anyhow::Error::from).map(|row| {
+                let ts: DateTime<Utc> = row.get("ts");
+                let ymd = ts.format("%Y-%m-%d").to_string();
+                (ymd, get_values(&row, &currencies, &fields))
+            });
+            // receiver is dropped when the client is gone
+            if tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// prices at exact timestamps in a single query, keyed by date
+pub async fn get_prices_many(
+    conn: &mut PoolConnection<Postgres>,
+    timestamps: &[DateTime<Utc>],
+    market: &str,
+    currencies: &Currencies,
+) -> Result<BTreeMap<String, BTreeMap<String, f64>>> {
+    let sql = format!(
+        "SELECT ts,{} FROM {} WHERE ts = ANY($1)",
+        currencies.as_vec().join(","),
+        get_table_name(market),
+    );
+    let rows = sqlx::query(&sql)
+        .bind(timestamps.to_vec())
+        .fetch_all(conn)
+        .await?;
+    let mut out = BTreeMap::new();
+    for row in rows {
+        let ts: DateTime<Utc> = row.get("ts");
+        let ymd = ts.format("%Y-%m-%d").to_string();
+        out.insert(ymd, get_values(&row, currencies, &Fields::default()));
+    }
+    Ok(out)
+}
+
+/// price of the market in the base market for each day,
+/// computed from their prices in the pivot currency, skipping gaps
+pub async fn get_cross_rates(
+    conn: &mut PoolConnection<Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    market: &str,
+    base: &str,
+    pivot: &str,
+) -> Result<BTreeMap<String, f64>> {
+    let sql = format!(
+        "SELECT a.ts, (a.{p} / b.{p})::float8 AS rate FROM {} a JOIN {} b ON a.ts = b.ts
+        WHERE a.ts >= $1 AND a.ts <= $2 AND a.{p} > 0 AND b.{p} > 0 ORDER BY a.ts",
+        get_table_name(market),
+        get_table_name(base),
+        p = pivot,
+    );
+    let rows = sqlx::query(&sql)
+        .bind(from)
+        .bind(to)
+        .fetch_all(conn)
+        .await?;
+    let mut out = BTreeMap::new();
+    for row in rows {
+        let ts: DateTime<Utc> = row.get("ts");
+        let rate: f64 = row.get("rate");
+        out.insert(ts.format("%Y-%m-%d").to_string(), rate);
+    }
+    Ok(out)
+}
+
+/// Bucket of the aggregated period
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interval {
+    Week,
+    Month,
+    Year,
+}
+
+impl std::str::FromStr for Interval {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "week" => Ok(Interval::Week),
+            "month" => Ok(Interval::Month),
+            "year" => Ok(Interval::Year),
+            _ => Err(format!("unknown interval {}", s)),
+        }
+    }
+}
+
+impl Interval {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Interval::Week => "week",
+            Interval::Month => "month",
+            Interval::Year => "year",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, schemars::JsonSchema)]
+pub struct Ohlc {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub mean: f64,
+}
+
+/// open, high, low, close and mean of each bucket, keyed by the first day of the bucket.
+/// gaps of the history (non-positive values) are not included
+pub async fn get_prices_aggregated(
+    conn: &mut PoolConnection<Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    interval: Interval,
+    market: &str,
+    currencies: &Currencies,
+    fields: &Fields,
+) -> Result<BTreeMap<String, BTreeMap<String, Ohlc>>> {
+    let columns = fields.columns(currencies);
+    let mut aggregates: Vec<String> = vec![];
+    for c in &columns {
+        aggregates.push(format!(
+            "(array_agg({c} ORDER BY ts) FILTER (WHERE {c} > 0))[1]::float8 AS {c}_open,
+            (max({c}) FILTER (WHERE {c} > 0))::float8 AS {c}_high,
+            (min({c}) FILTER (WHERE {c} > 0))::float8 AS {c}_low,
+            (array_agg({c} ORDER BY ts DESC) FILTER (WHERE {c} > 0))[1]::float8 AS {c}_close,
+            (avg({c}) FILTER (WHERE {c} > 0))::float8 AS {c}_mean",
+            c = c
+        ));
+    }
+    let sql = format!(
+        "SELECT date_trunc('{}', ts AT TIME ZONE 'UTC') AS bucket, {} FROM {}
+        WHERE ts >= $1 AND ts <= $2 GROUP BY bucket ORDER BY bucket",
+        interval.as_sql(),
+        aggregates.join(", "),
+        get_table_name(market),
+    );
+    let rows = sqlx::query(&sql)
+        .bind(from)
+        .bind(to)
+        .fetch_all(conn)
+        .await?;
+    let mut out = BTreeMap::new();
+    for row in rows {
+        let bucket: NaiveDateTime = row.get("bucket");
         let mut m = BTreeMap::new();
-        for currency in currencies.iter() {
-            let num: BigDecimal = row.get(currency.as_str());
-            let val: f64 = num.to_string().parse().unwrap_or(0.0);
-            m.insert(currency.to_string(), val);
+        for c in &columns {
+            let get =
+                |suffix: &str| -> Option<f64> { row.get(format!("{}_{}", c, suffix).as_str()) };
+            if let (Some(open), Some(high), Some(low), Some(close), Some(mean)) = (
+                get("open"),
+                get("high"),
+                get("low"),
+                get("close"),
+                get("mean"),
+            ) {
+                m.insert(
+                    c.clone(),
+                    Ohlc {
+                        open,
+                        high,
+                        low,
+                        close,
+                        mean,
+                    },
+                );
+            }
         }
-        out.insert(ymd, m);
+        out.insert(bucket.format("%Y-%m-%d").to_string(), m);
     }
-    out
+    Ok(out)
+}
+
+/// time of the latest stored hourly prices of the market
+pub async fn get_hourly_last(
+    conn: &mut PoolConnection<Postgres>,
+    market: &str,
+) -> Result<Option<DateTime<Utc>>> {
+    let sql = format!("SELECT max(ts) FROM {}", get_hourly_table_name(market));
+    let ts: Option<DateTime<Utc>> = sqlx::query_scalar(&sql).fetch_one(conn).await?;
+    Ok(ts)
+}
+
+/// prices of the market at the start of the hour, none when the hour is not stored
+pub async fn get_hourly(
+    conn: &mut PoolConnection<Postgres>,
+    hour: DateTime<Utc>,
+    market: &str,
+    currencies: &Currencies,
+) -> Result<Option<BTreeMap<String, f64>>> {
+    let sql = format!(
+        "SELECT {} FROM {} WHERE ts = $1",
+        currencies.as_vec().join(","),
+        get_hourly_table_name(market),
+    );
+    let row = sqlx::query(&sql).bind(hour).fetch_optional(conn).await?;
+    Ok(row.map(|row| get_values(&row, currencies, &Fields::default())))
+}
+
+pub async fn insert_hourly(
+    conn: &mut PoolConnection<Postgres>,
+    hour: DateTime<Utc>,
+    market: &str,
+    prices: &HashMap<String, f64>,
+) -> Result<()> {
+    insert_into(conn, &get_hourly_table_name(market), hour, prices).await
 }
 
 pub async fn has_price(
@@ -113,6 +742,15 @@ pub async fn insert(
     timestamp: DateTime<Utc>,
     market: &str,
     prices: &HashMap<String, f64>,
+) -> Result<()> {
+    insert_into(conn, &get_table_name(market), timestamp, prices).await
+}
+
+async fn insert_into(
+    conn: &mut PoolConnection<Postgres>,
+    table: &str,
+    timestamp: DateTime<Utc>,
+    prices: &HashMap<String, f64>,
 ) -> Result<()> {
     let mut fields: Vec<String> = vec![];
     let mut values: Vec<String> = vec![];
@@ -122,7 +760,7 @@ pub async fn insert(
     }
     let sql = format!(
         "INSERT INTO {} (ts,{}) VALUES ($1,{}) ON CONFLICT DO NOTHING",
-        get_table_name(market),
+        table,
         fields.join(", "),
         values.join(", ")
     );
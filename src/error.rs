@@ -0,0 +1,171 @@
+use crate::resolve::ResolveError;
+use crate::telemetry::RequestId;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+use tracing::error;
+
+/// Failure of the API request, returned by the handlers as `tide::Error`
+/// and rendered by `ErrorMiddleware` as
+/// `{"error":{"status":400,"code":"invalid_input","message":"...","request_id":"..."}}`.
+/// Codes are stable and meant for the clients, messages are for humans
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub suggestions: Option<Vec<String>>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: &str) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_owned(),
+            suggestions: None,
+        }
+    }
+
+    pub fn input(message: &str) -> Self {
+        Self::new(StatusCode::BadRequest, "invalid_input", message)
+    }
+
+    pub fn not_found(code: &'static str, message: &str) -> Self {
+        Self::new(StatusCode::NotFound, code, message)
+    }
+
+    /// the detail of the failure is logged, clients get the generic message
+    pub fn internal(detail: &str) -> Self {
+        error!("internal error: {}", detail);
+        Self::new(
+            StatusCode::InternalServerError,
+            "internal",
+            "internal error",
+        )
+    }
+
+    /// error for the status of the response that has no error of its own
+    pub fn from_status(status: StatusCode, message: &str) -> Self {
+        let code = match status {
+            StatusCode::BadRequest => "invalid_input",
+            StatusCode::Unauthorized => "unauthorized",
+            StatusCode::Forbidden => "forbidden",
+            StatusCode::NotFound => "not_found",
+            StatusCode::MethodNotAllowed => "method_not_allowed",
+            StatusCode::PayloadTooLarge => "payload_too_large",
+            StatusCode::UnprocessableEntity => "invalid_input",
+            StatusCode::TooManyRequests => "rate_limited",
+            _ if status.is_server_error() => "internal",
+            _ => "error",
+        };
+        Self::new(status, code, message)
+    }
+
+    fn body(&self, request_id: &str) -> ErrorBody {
+        ErrorBody {
+            error: ErrorDetails {
+                status: self.status as u16,
+                code: self.code,
+                message: self.message.clone(),
+                request_id: request_id.to_owned(),
+                suggestions: self.suggestions.clone(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<ResolveError> for ApiError {
+    fn from(e: ResolveError) -> Self {
+        let code = match e {
+            ResolveError::Unknown(_) => "unknown_market",
+            ResolveError::Ambiguous(_, _) => "ambiguous_market",
+            ResolveError::NotConfigured(_, _) => "market_not_configured",
+        };
+        // the query naming several markets is to be fixed by the client, others are not found
+        let status = match e {
+            ResolveError::Ambiguous(_, _) => StatusCode::BadRequest,
+            _ => StatusCode::NotFound,
+        };
+        let mut err = Self::new(status, code, &e.to_string());
+        err.suggestions = Some(e.suggestions());
+        err
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorDetails {
+    status: u16,
+    code: &'static str,
+    message: String,
+    request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestions: Option<Vec<String>>,
+}
+
+/// Failure of one item of the batch, coded as the single request would be
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ItemError {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestions: Option<Vec<String>>,
+}
+
+impl From<ApiError> for ItemError {
+    fn from(e: ApiError) -> Self {
+        Self {
+            code: e.code,
+            message: e.message,
+            suggestions: e.suggestions,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+    error: ErrorDetails,
+}
+
+/// Renders every failed response as the JSON error,
+/// should be added after `TraceMiddleware` to know the request id
+#[derive(Debug, Default, Clone)]
+pub struct ErrorMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ErrorMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let rqid = match req.ext::<RequestId>() {
+            Some(x) => x.0.clone(),
+            None => String::new(),
+        };
+        let mut res = next.run(req).await;
+        let status = res.status();
+        let err = match res.downcast_error::<ApiError>() {
+            Some(e) => e.clone(),
+            None => match res.error() {
+                // details of unexpected failures stay in the logs
+                Some(e) if status.is_server_error() => ApiError::internal(&e.to_string()),
+                Some(e) => ApiError::from_status(status, &e.to_string()),
+                None if status.is_client_error() || status.is_server_error() => {
+                    if res.is_empty() == Some(false) {
+                        return Ok(res);
+                    }
+                    ApiError::from_status(status, status.canonical_reason())
+                }
+                None => return Ok(res),
+            },
+        };
+        res.set_status(err.status);
+        res.set_body(Body::from_json(&err.body(&rqid))?);
+        Ok(res)
+    }
+}
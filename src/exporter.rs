@@ -1,24 +1,57 @@
 use super::{db, fetch};
-use crate::{Currencies, Markets};
+use crate::{Currencies, Market, Markets};
 use anyhow::Result;
 use async_std::task;
 use chrono::prelude::*;
 use chrono::{Datelike, Duration, Utc};
 use sqlx::pool::PoolConnection;
 use sqlx::Postgres;
-use tracing::info;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
 
 pub async fn init(
     conn: &mut PoolConnection<Postgres>,
     markets: &Markets,
     currencies: &Currencies,
 ) -> Result<()> {
+    db::create_markets_table(conn).await?;
     for market in markets.iter() {
         db::create_table(conn, market.name.as_str(), currencies).await?;
+        db::create_hourly_table(conn, market.name.as_str(), currencies).await?;
     }
     Ok(())
 }
 
+/// lower bound of the history: configured, previously discovered,
+/// or requested from the provider and saved for the next runs
+pub async fn earliest(
+    conn: &mut PoolConnection<Postgres>,
+    market: &Market,
+    currencies: &Currencies,
+) -> Result<NaiveDate> {
+    if let Some(dt) = market.earliest {
+        return Ok(dt);
+    }
+    if let Some(dt) = db::get_earliest(conn, &market.name).await? {
+        return Ok(dt);
+    }
+    let currency = currencies
+        .iter()
+        .next()
+        .map(|x| x.as_str())
+        .unwrap_or("usd");
+    let (m, c) = (market.clone(), currency.to_owned());
+    match task::spawn_blocking(move || fetch::earliest(&m, &c)).await {
+        Ok(Some(dt)) => {
+            info!("Market {}: discovered earliest date {}", market.name, dt);
+            db::set_earliest(conn, &market.name, dt).await?;
+            Ok(dt)
+        }
+        Ok(None) => Err(anyhow::anyhow!("no earliest date for {}", market.name)),
+        Err(e) => Err(e),
+    }
+}
+
 pub async fn update_history(
     conn: &mut PoolConnection<Postgres>,
     markets: &Markets,
@@ -32,13 +65,20 @@ pub async fn update_history(
         let mut days = 0;
         let now = Utc::now();
         let start = Utc.ymd(now.year(), now.month(), now.day());
+        let earliest = match earliest(conn, market, currencies).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Market {}: earliest date is unknown: {}", &market.name, e);
+                NaiveDate::from_ymd(now.year(), 1, 1)
+            }
+        };
         println!(
             "Market {}: updating history since {:?}",
-            &market.name, market.earliest
+            &market.name, earliest
         );
+        let earliest = Date::<Utc>::from_utc(earliest, Utc);
         loop {
             let dt = start + Duration::days(days);
-            let earliest = Date::<Utc>::from_utc(market.earliest, Utc);
             if dt < earliest {
                 break;
             }
@@ -58,20 +98,31 @@ pub async fn update_history(
                 m,
                 d
             );
-            match fetch::history(market.name.as_str(), y, m, d, currencies) {
+            // provider requests block and wait out its rate limits, away from the executor
+            let (mk, cs) = (market.clone(), currencies.clone());
+            match task::spawn_blocking(move || fetch::history(&mk, y, m, d, &cs)).await {
                 Ok(prices) => {
                     db::insert(conn, timestamp, &market.name, &prices).await?;
                 }
-                Err(_) => {
-                    if no_gaps {
-                        let gaps_map = currencies.as_map();
-                        db::insert(conn, timestamp, &market.name, &gaps_map).await?;
+                Err(e) => match e.downcast_ref::<fetch::FetchError>() {
+                    Some(fetch::FetchError::NoMarketData(_, _)) => {
+                        // the day will never have prices, marking it as a gap
+                        // so it would not be requested again
+                        if no_gaps {
+                            let gaps_map = currencies.as_map();
+                            db::insert(conn, timestamp, &market.name, &gaps_map).await?;
+                        }
                     }
-                }
+                    _ => {
+                        // network or provider failures, the day is retried on the next run
+                        warn!("failed price for {}: {}", market.name.as_str(), e);
+                    }
+                },
             };
             task::sleep(std::time::Duration::from_secs(1)).await;
             days = days - 1;
         }
+        db::set_indexed(conn, &market.name, Utc::now()).await?;
         info!(
             "Indexing of {} market took {:?}",
             &market.name,
@@ -80,3 +131,131 @@ pub async fn update_history(
     }
     Ok(())
 }
+
+/// market cap and volume of the days stored before they were indexed,
+/// once per market. the failed days are retried on the next run
+pub async fn backfill_fields(
+    conn: &mut PoolConnection<Postgres>,
+    markets: &Markets,
+    currencies: &Currencies,
+) -> Result<()> {
+    for market in markets.iter() {
+        if db::is_backfilled(conn, &market.name).await? {
+            continue;
+        }
+        let days = db::get_missing_fields(conn, &market.name, currencies).await?;
+        info!(
+            "Market {}: backfilling market cap and volume of {} days",
+            &market.name,
+            days.len()
+        );
+        let mut failed = false;
+        for ts in days {
+            let (mk, cs) = (market.clone(), currencies.clone());
+            let (y, m, d) = (ts.year(), ts.month(), ts.day());
+            match task::spawn_blocking(move || fetch::history(&mk, y, m, d, &cs)).await {
+                Ok(values) => {
+                    // stored prices are kept as they are
+                    let values: HashMap<String, f64> = values
+                        .into_iter()
+                        .filter(|(column, _)| !currencies.contains(column))
+                        .collect();
+                    if !values.is_empty() {
+                        db::update_fields(conn, ts, &market.name, &values).await?;
+                    }
+                }
+                Err(e) => match e.downcast_ref::<fetch::FetchError>() {
+                    Some(fetch::FetchError::NoMarketData(_, _)) => {}
+                    _ => {
+                        warn!("failed backfill for {}: {}", market.name.as_str(), e);
+                        failed = true;
+                    }
+                },
+            }
+            task::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        if !failed {
+            db::set_backfilled(conn, &market.name).await?;
+        }
+    }
+    Ok(())
+}
+
+/// hourly prices since the last stored hour, as far back as the provider keeps them.
+/// they serve the days starting at the local midnight of other time zones
+pub async fn update_hourly(
+    conn: &mut PoolConnection<Postgres>,
+    markets: &Markets,
+    currencies: &Currencies,
+) -> Result<()> {
+    for market in markets.iter() {
+        let now = Utc::now();
+        let oldest = now - Duration::days(fetch::HOURLY_DAYS);
+        let from = match db::get_hourly_last(conn, &market.name).await? {
+            Some(x) if x > oldest => x + Duration::hours(1),
+            _ => oldest,
+        };
+        if from > now {
+            continue;
+        }
+        let mut rows: BTreeMap<DateTime<Utc>, HashMap<String, f64>> = BTreeMap::new();
+        let mut failed = false;
+        for currency in currencies.iter() {
+            let (mk, c) = (market.clone(), currency.clone());
+            match task::spawn_blocking(move || fetch::hourly(&mk, &c, from, now)).await {
+                Ok(prices) => {
+                    for (hour, price) in prices {
+                        rows.entry(hour)
+                            .or_default()
+                            .insert(currency.clone(), price);
+                    }
+                }
+                Err(e) => {
+                    warn!("failed hourly prices for {}: {}", market.name.as_str(), e);
+                    failed = true;
+                    break;
+                }
+            }
+            task::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        // hours with some currencies missing would never be completed, they are retried instead
+        if failed {
+            continue;
+        }
+        let count = currencies.as_vec().len();
+        rows.retain(|_, prices| prices.len() == count);
+        for (hour, prices) in &rows {
+            db::insert_hourly(conn, *hour, &market.name, prices).await?;
+        }
+        info!(
+            "Market {}: {} hourly prices since {}",
+            &market.name,
+            rows.len(),
+            from
+        );
+    }
+    Ok(())
+}
+
+/// seconds between the updates of the stored prices
+const UPDATE_SECS: u64 = 3600;
+
+/// keeps the daily and hourly prices updated while the server runs
+pub async fn schedule(pool: sqlx::Pool<Postgres>, markets: Markets, currencies: Currencies) {
+    loop {
+        task::sleep(std::time::Duration::from_secs(UPDATE_SECS)).await;
+        let mut conn = match pool.acquire().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("scheduled update: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = update_history(&mut conn, &markets, &currencies, false).await {
+            warn!("scheduled update of history: {}", e);
+        }
+        if let Err(e) = update_hourly(&mut conn, &markets, &currencies).await {
+            warn!("scheduled update of hourly prices: {}", e);
+        }
+    }
+}
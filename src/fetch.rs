@@ -1,14 +1,19 @@
-use crate::{Currencies, Markets};
+use crate::{Contract, Currencies, Field, Fields, Market, Markets};
 use anyhow::Result;
 use cached::proc_macro::cached;
-use serde::Deserialize;
-use std::collections::HashMap;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
 use tracing::warn;
 use ureq::{Agent, AgentBuilder};
 
+/// seconds the responses of `cached_get` are reused
+pub const CACHE_SECS: i64 = 300;
+
+/// response body with the time it was fetched
 #[cached(time = 300)]
-pub fn cached_get(url: String) -> String {
+pub fn cached_get(url: String) -> (String, DateTime<Utc>) {
     let agent: Agent = AgentBuilder::new()
         .timeout_read(Duration::from_secs(5))
         .build();
@@ -16,56 +21,358 @@ pub fn cached_get(url: String) -> String {
         Ok(x) => x.into_string().unwrap_or("{}".to_owned()),
         Err(_) => "{}".to_owned(),
     };
-    response
+    (response, Utc::now())
+}
+
+pub type CurrentMarkets = HashMap<String, HashMap<String, f64>>;
+
+fn include_fields(fields: &Fields) -> String {
+    let mut flags = String::new();
+    if fields.contains(Field::MarketCap) {
+        flags.push_str("&include_market_cap=true");
+    }
+    if fields.contains(Field::Volume) {
+        flags.push_str("&include_24hr_vol=true");
+    }
+    flags
+}
+
+/// current prices of all markets as JSON, tokens are keyed by their market name.
+/// market cap and volume are keyed as `<currency>_market_cap` and `<currency>_24h_vol`
+pub fn current(markets: &Markets, currencies: &Currencies, fields: &Fields) -> String {
+    current_at(markets, currencies, fields).0
+}
+
+/// current prices with the time of the oldest response they are made of
+pub fn current_at(
+    markets: &Markets,
+    currencies: &Currencies,
+    fields: &Fields,
+) -> (String, DateTime<Utc>) {
+    let mut fetched_at = Utc::now();
+    let coins: Vec<String> = markets
+        .iter()
+        .filter(|m| m.contract.is_none())
+        .map(|m| m.name.clone())
+        .collect();
+    let vs_currencies = currencies.as_vec().join("%2C");
+    let mut out: CurrentMarkets = HashMap::new();
+    if !coins.is_empty() {
+        let (raw, tm) = cached_get(format!(
+            "https://api.coingecko.com/api/v3/simple/price?ids={}&vs_currencies={}{}",
+            coins.join("%2C"),
+            vs_currencies,
+            include_fields(fields)
+        ));
+        fetched_at = fetched_at.min(tm);
+        if let Ok(prices) = serde_json::from_str::<CurrentMarkets>(&raw) {
+            out.extend(prices);
+        }
+    }
+
+    let mut chains: HashMap<String, Vec<&Market>> = HashMap::new();
+    for market in markets.iter() {
+        if let Some(contract) = &market.contract {
+            chains
+                .entry(contract.chain.clone())
+                .or_default()
+                .push(market);
+        }
+    }
+    for (chain, tokens) in chains {
+        let addresses: Vec<String> = tokens
+            .iter()
+            .filter_map(|m| m.contract.as_ref().map(|c| c.address.clone()))
+            .collect();
+        let (raw, tm) = cached_get(format!(
+            "https://api.coingecko.com/api/v3/simple/token_price/{}?contract_addresses={}&vs_currencies={}{}",
+            chain,
+            addresses.join("%2C"),
+            vs_currencies,
+            include_fields(fields)
+        ));
+        fetched_at = fetched_at.min(tm);
+        let prices: CurrentMarkets = match serde_json::from_str(&raw) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        for market in tokens {
+            if let Some(contract) = &market.contract {
+                if let Some(p) = prices.get(&contract.address) {
+                    out.insert(market.name.clone(), p.clone());
+                }
+            }
+        }
+    }
+    let out = serde_json::to_string(&out).unwrap_or("{}".to_owned());
+    (out, fetched_at)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Coin {
+    pub id: String,
+    pub symbol: String,
+    pub name: String,
 }
 
-pub fn current(markets: &Markets, currencies: &Currencies) -> String {
-    cached_get(format!(
-        "https://api.coingecko.com/api/v3/simple/price?ids={}&vs_currencies={}",
-        markets.as_vec().join("%2C"),
-        currencies.as_vec().join("%2C")
-    ))
+/// full coin list of the provider, blocking for up to 30 seconds
+pub fn coins() -> Result<Vec<Coin>> {
+    let agent: Agent = AgentBuilder::new()
+        .timeout_read(Duration::from_secs(30))
+        .build();
+    let list: Vec<Coin> = agent
+        .get("https://api.coingecko.com/api/v3/coins/list")
+        .call()?
+        .into_json()?;
+    Ok(list)
+}
+
+/// Failures of the provider that are not network or protocol errors
+#[derive(Clone, Debug, PartialEq)]
+pub enum FetchError {
+    /// the coin is known, but provider has no prices for the date
+    NoMarketData(String, NaiveDate),
+    /// response is not matching the expected structure
+    InvalidResponse(String),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NoMarketData(market, dt) => write!(f, "no market data for {} at {}", market, dt),
+            Self::InvalidResponse(e) => write!(f, "invalid response: {}", e),
+        }
+    }
 }
 
+impl std::error::Error for FetchError {}
+
+/// values are missing (`null`) for some currencies, mostly in the early history
 #[derive(Clone, Debug, Deserialize)]
 pub struct MarketData {
-    current_price: HashMap<String, f64>,
+    #[serde(default)]
+    current_price: HashMap<String, Option<f64>>,
+    #[serde(default)]
+    market_cap: HashMap<String, Option<f64>>,
+    #[serde(default)]
+    total_volume: HashMap<String, Option<f64>>,
 }
 
+/// `market_data` is absent when coin was not traded at the date
 #[derive(Clone, Debug, Deserialize)]
 pub struct HistoryResponse {
-    market_data: MarketData,
+    market_data: Option<MarketData>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MarketChartResponse {
+    prices: Vec<(f64, f64)>,
+    #[serde(default)]
+    market_caps: Vec<(f64, f64)>,
+    #[serde(default)]
+    total_volumes: Vec<(f64, f64)>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CoinResponse {
+    genesis_date: Option<String>,
+}
+
+/// first date of the market that provider has prices for,
+/// taken from the full market chart with genesis date as a fallback
+pub fn earliest(market: &Market, currency: &str) -> Result<Option<NaiveDate>> {
+    let agent: Agent = AgentBuilder::new()
+        .timeout_read(Duration::from_secs(30))
+        .build();
+    let url = match &market.contract {
+        Some(c) => format!(
+            "https://api.coingecko.com/api/v3/coins/{}/contract/{}/market_chart?vs_currency={}&days=max",
+            c.chain, c.address, currency
+        ),
+        None => format!(
+            "https://api.coingecko.com/api/v3/coins/{}/market_chart?vs_currency={}&days=max",
+            market.name, currency
+        ),
+    };
+    let chart: MarketChartResponse = agent.get(url.as_str()).call()?.into_json()?;
+    if let Some((ms, _)) = chart.prices.first() {
+        let dt = Utc.timestamp_millis(*ms as i64);
+        return Ok(Some(dt.naive_utc().date()));
+    }
+    if market.contract.is_some() {
+        return Ok(None);
+    }
+    let url = format!(
+        "https://api.coingecko.com/api/v3/coins/{}?localization=false&tickers=false&market_data=false&community_data=false&developer_data=false",
+        market.name
+    );
+    let coin: CoinResponse = agent.get(url.as_str()).call()?.into_json()?;
+    Ok(coin
+        .genesis_date
+        .and_then(|x| NaiveDate::parse_from_str(&x, "%Y-%m-%d").ok()))
+}
+
+/// pause between the requests of one token day, the provider limits the requests per minute
+const CONTRACT_THROTTLE: Duration = Duration::from_millis(1500);
+/// longest wait for the provider rate limit before giving up on the day
+const MAX_RETRY_AFTER: u64 = 120;
+
+/// market chart of the coin or contract, the rate limited request is repeated once after the wait.
+/// blocks the thread, to be called from `task::spawn_blocking`
+fn market_chart(agent: &Agent, url: &str) -> Result<MarketChartResponse> {
+    match agent.get(url).call() {
+        Err(ureq::Error::Status(429, response)) => {
+            let secs = response
+                .header("retry-after")
+                .and_then(|x| x.parse::<u64>().ok())
+                .unwrap_or(60)
+                .min(MAX_RETRY_AFTER);
+            warn!("rate limited by the provider, retrying in {}s", secs);
+            std::thread::sleep(Duration::from_secs(secs));
+            Ok(agent.get(url).call()?.into_json()?)
+        }
+        Err(e) => Err(e.into()),
+        Ok(x) => Ok(x.into_json()?),
+    }
+}
+
+/// token prices, market caps and volumes of the day, one throttled request per currency
+/// as market chart of the contract has a single currency
+fn history_contract(
+    contract: &Contract,
+    y: i32,
+    m: u32,
+    d: u32,
+    currencies: &Currencies,
+) -> Result<HashMap<String, f64>> {
+    let from = Utc.ymd(y, m, d).and_hms(0, 0, 0).timestamp();
+    let to = from + 86400;
+    let agent: Agent = AgentBuilder::new()
+        .timeout_read(Duration::from_secs(5))
+        .build();
+    let mut out: HashMap<String, f64> = HashMap::new();
+    for (i, currency) in currencies.iter().enumerate() {
+        if i > 0 {
+            std::thread::sleep(CONTRACT_THROTTLE);
+        }
+        let url = format!(
+            "https://api.coingecko.com/api/v3/coins/{}/contract/{}/market_chart/range?vs_currency={}&from={}&to={}",
+            contract.chain, contract.address, currency, from, to,
+        );
+        let response = market_chart(&agent, &url)?;
+        if let Some((_, price)) = response.prices.first() {
+            out.insert(currency.clone(), *price);
+        }
+        if let Some((_, cap)) = response.market_caps.first() {
+            out.insert(Field::MarketCap.column(currency), *cap);
+        }
+        if let Some((_, vol)) = response.total_volumes.first() {
+            out.insert(Field::Volume.column(currency), *vol);
+        }
+    }
+    if out.is_empty() {
+        let dt = NaiveDate::from_ymd(y, m, d);
+        return Err(FetchError::NoMarketData(contract.address.clone(), dt).into());
+    }
+    Ok(out)
+}
+
+/// days back from now the provider keeps the hourly prices for,
+/// older ranges of the market chart are daily
+pub const HOURLY_DAYS: i64 = 89;
+
+/// prices of the market in the currency at the start of each hour of the range,
+/// keyed by the hour. the range should be within `HOURLY_DAYS` from now
+pub fn hourly(
+    market: &Market,
+    currency: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<BTreeMap<DateTime<Utc>, f64>> {
+    let url = match &market.contract {
+        Some(c) => format!(
+            "https://api.coingecko.com/api/v3/coins/{}/contract/{}/market_chart/range?vs_currency={}&from={}&to={}",
+            c.chain, c.address, currency, from.timestamp(), to.timestamp(),
+        ),
+        None => format!(
+            "https://api.coingecko.com/api/v3/coins/{}/market_chart/range?vs_currency={}&from={}&to={}",
+            market.name, currency, from.timestamp(), to.timestamp(),
+        ),
+    };
+    let agent: Agent = AgentBuilder::new()
+        .timeout_read(Duration::from_secs(30))
+        .build();
+    let chart = market_chart(&agent, &url)?;
+    // samples are rarely on the hour and recent ranges are more detailed than hourly,
+    // the sample closest to the start of the hour is kept
+    let mut closest: BTreeMap<DateTime<Utc>, (i64, f64)> = BTreeMap::new();
+    for (ms, price) in chart.prices {
+        let ts = match Utc.timestamp_millis_opt(ms as i64).single() {
+            Some(x) => x,
+            None => return Err(FetchError::InvalidResponse(format!("timestamp {}", ms)).into()),
+        };
+        let rounded = ts + chrono::Duration::minutes(30);
+        let hour = rounded.date().and_hms(rounded.hour(), 0, 0);
+        let distance = (ts - hour).num_seconds().abs();
+        match closest.get(&hour) {
+            Some((d, _)) if *d <= distance => {}
+            _ => {
+                closest.insert(hour, (distance, price));
+            }
+        }
+    }
+    Ok(closest.into_iter().map(|(h, (_, p))| (h, p)).collect())
 }
 
+/// prices of the day with market cap and volume, keyed by database column
 pub fn history(
-    market: &str,
+    market: &Market,
     y: i32,
     m: u32,
     d: u32,
     currencies: &Currencies,
 ) -> Result<HashMap<String, f64>> {
+    if let Some(contract) = &market.contract {
+        return history_contract(contract, y, m, d, currencies);
+    }
     let url = format!(
         "https://api.coingecko.com/api/v3/coins/{}/history?date={:02}-{:02}-{}",
-        market, d, m, y,
+        market.name, d, m, y,
     );
     let agent: Agent = AgentBuilder::new()
         .timeout_read(Duration::from_secs(5))
         .build();
     let raw = agent.get(url.as_str()).call()?.into_string()?;
-    let result = str::replace(raw.as_str(), "null", "0");
-    let response: HistoryResponse = match serde_json::from_str(result.as_str()) {
+    let response: HistoryResponse = match serde_json::from_str(raw.as_str()) {
         Ok(x) => x,
         Err(e) => {
-            warn!("LAST RESPONSE: {}", result);
+            warn!("LAST RESPONSE: {}", raw);
             warn!("ERROR: {}", e);
-            return Err(anyhow::Error::from(e));
+            return Err(FetchError::InvalidResponse(e.to_string()).into());
+        }
+    };
+    let data = match &response.market_data {
+        Some(x) => x,
+        None => {
+            let dt = NaiveDate::from_ymd(y, m, d);
+            return Err(FetchError::NoMarketData(market.name.clone(), dt).into());
         }
     };
     let mut out: HashMap<String, f64> = HashMap::new();
     for currency in currencies.iter() {
-        if let Some(val) = response.market_data.current_price.get(currency) {
+        if let Some(Some(val)) = data.current_price.get(currency) {
             out.insert(currency.clone(), *val);
         }
+        if let Some(Some(val)) = data.market_cap.get(currency) {
+            out.insert(Field::MarketCap.column(currency), *val);
+        }
+        if let Some(Some(val)) = data.total_volume.get(currency) {
+            out.insert(Field::Volume.column(currency), *val);
+        }
+    }
+    if out.is_empty() {
+        let dt = NaiveDate::from_ymd(y, m, d);
+        return Err(FetchError::NoMarketData(market.name.clone(), dt).into());
     }
-    Ok(out.clone())
+    Ok(out)
 }
@@ -0,0 +1,99 @@
+use crate::api;
+use crate::error::ApiError;
+use crate::{fetch, Currencies, Fields, State};
+use anyhow::Result;
+use async_std::task;
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tide::Request;
+use tracing::{info, warn};
+
+pub type Snapshot = BTreeMap<String, BTreeMap<String, f64>>;
+
+/// Prices of the market that are new to the client
+#[derive(Clone, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+pub struct Update {
+    pub market: String,
+    /// unix time the change was noticed
+    pub ts: i64,
+    pub prices: BTreeMap<String, f64>,
+}
+
+/// current snapshot of all markets, fetched outside of the async executor
+pub async fn snapshot(state: &State) -> Result<Snapshot> {
+    let markets = state.markets.clone();
+    let currencies = state.currencies.clone();
+    let val =
+        task::spawn_blocking(move || fetch::current(&markets, &currencies, &Fields::default()))
+            .await;
+    Ok(serde_json::from_str(&val)?)
+}
+
+/// prices of the markets that differ from the last sent ones, they are remembered as sent
+pub fn changes(
+    last: &mut HashMap<String, BTreeMap<String, f64>>,
+    snapshot: &Snapshot,
+    markets: &[String],
+    currencies: &Currencies,
+) -> Vec<Update> {
+    let ts = Utc::now().timestamp();
+    let mut out: Vec<Update> = vec![];
+    for market in markets {
+        let prices = match snapshot.get(market) {
+            Some(x) => api::select_columns(x, currencies, &Fields::default()),
+            None => continue,
+        };
+        if prices.is_empty() || last.get(market) == Some(&prices) {
+            continue;
+        }
+        last.insert(market.clone(), prices.clone());
+        out.push(Update {
+            market: market.clone(),
+            ts,
+            prices,
+        });
+    }
+    out
+}
+
+/// server-sent events: `prices` for each market when its prices change,
+/// `heartbeat` with the unix time when nothing changed since the previous check
+pub async fn stream(req: Request<State>) -> tide::Result {
+    let markets = api::query_markets(&req)?;
+    let currencies = api::query_currencies(&req).map_err(|e| ApiError::input(&e))?;
+    info!("stream markets={}", markets.join(","));
+    Ok(tide::sse::upgrade(req, move |req, sender| {
+        let markets = markets.clone();
+        let currencies = currencies.clone();
+        async move {
+            let state = req.state();
+            let interval = Duration::from_secs(state.stream_interval);
+            let mut last = HashMap::new();
+            loop {
+                let updates = match snapshot(state).await {
+                    Ok(x) => changes(&mut last, &x, &markets, &currencies),
+                    Err(e) => {
+                        warn!("stream snapshot: {}", e);
+                        vec![]
+                    }
+                };
+                // sending fails once the client is gone
+                if updates.is_empty() {
+                    let ts = Utc::now().timestamp().to_string();
+                    if sender.send("heartbeat", ts, None).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                for update in updates {
+                    let id = update.ts.to_string();
+                    let data = serde_json::to_string(&update)?;
+                    if sender.send("prices", data, Some(&id)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                task::sleep(interval).await;
+            }
+        }
+    }))
+}
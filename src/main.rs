@@ -1,36 +1,89 @@
 pub mod api;
 pub mod args;
+pub mod auth;
+pub mod cache;
+pub mod compress;
 pub mod db;
+pub mod error;
 pub mod exporter;
 pub mod fetch;
+pub mod live;
 pub mod metrics;
+pub mod openapi;
+pub mod resolve;
+pub mod stats;
+pub mod stream;
 pub mod telemetry;
+pub mod ws;
 
-use chrono::{Datelike, NaiveDate, Utc};
-use std::collections::HashMap;
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, HashMap};
 use tracing::info;
 
+/// ERC-20 (or similar) token, identified by the platform and the contract address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contract {
+    pub chain: String,
+    pub address: String,
+}
+
+impl Contract {
+    pub fn new(chain: &str, address: &str) -> Self {
+        Self {
+            chain: chain.trim().to_lowercase(),
+            address: address.trim().to_lowercase(),
+        }
+    }
+
+    /// stable name of the market, safe to be used in table names
+    pub fn key(&self) -> String {
+        let sanitize = |s: &str| -> String {
+            s.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect()
+        };
+        format!("{}_{}", sanitize(&self.chain), sanitize(&self.address))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Market {
     pub name: String,
-    pub earliest: NaiveDate,
+    /// configured lower bound of the history,
+    /// discovered from the provider when it is not set
+    pub earliest: Option<NaiveDate>,
+    pub contract: Option<Contract>,
 }
 
 impl Market {
-    pub fn new(src: &str) -> Self {
-        let parts: Vec<&str> = src.split(":").collect();
-        let now = Utc::now();
-        let start = NaiveDate::from_ymd(now.year(), 1, 1);
-        let (name, earliest) = if parts.len() == 1 {
-            (src.to_owned(), start)
+    /// market is either coin id (`ethereum`) or token (`contract:<chain>:<address>`),
+    /// optionally followed by the earliest date (`ethereum:2020-01-01`)
+    pub fn new(src: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut parts: Vec<&str> = src.split(":").collect();
+        let contract = if parts.len() >= 3 && parts[0] == "contract" {
+            let c = Contract::new(parts[1], parts[2]);
+            parts.drain(0..3);
+            Some(c)
         } else {
-            let dt = match NaiveDate::parse_from_str(parts[1], "%Y-%m-%d") {
-                Ok(x) => x,
-                Err(_) => start,
-            };
-            (parts[0].to_owned(), dt)
+            None
+        };
+        let name = match &contract {
+            Some(c) => c.key(),
+            None => parts.remove(0).to_owned(),
         };
-        Self { name, earliest }
+        let earliest = match parts.len() {
+            0 => None,
+            1 => match NaiveDate::parse_from_str(parts[0], "%Y-%m-%d") {
+                Ok(x) => Some(x),
+                Err(e) => return Err(format!("market {} earliest date: {}", name, e).into()),
+            },
+            _ => return Err(format!("invalid market {}", src).into()),
+        };
+        Ok(Self {
+            name,
+            earliest,
+            contract,
+        })
     }
 }
 
@@ -39,9 +92,11 @@ pub struct Markets(Vec<Market>);
 impl std::str::FromStr for Markets {
     type Err = Box<dyn std::error::Error>;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Markets(
-            s.split(",").map(|x| Market::new(x.trim())).collect(),
-        ))
+        let mut out = vec![];
+        for x in s.split(",") {
+            out.push(Market::new(x.trim())?);
+        }
+        Ok(Markets(out))
     }
 }
 impl Markets {
@@ -51,6 +106,113 @@ impl Markets {
     pub fn iter(&self) -> std::slice::Iter<'_, Market> {
         self.0.iter()
     }
+    pub fn get(&self, name: &str) -> Option<&Market> {
+        self.0.iter().find(|x| x.name == name)
+    }
+    pub fn get_contract(&self, chain: &str, address: &str) -> Option<&Market> {
+        let contract = Contract::new(chain, address);
+        self.0
+            .iter()
+            .find(|x| x.contract.as_ref() == Some(&contract))
+    }
+}
+
+/// Tickers or other short names of the markets, i.e. `eth=ethereum`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aliases(HashMap<String, String>);
+impl std::str::FromStr for Aliases {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut m = HashMap::new();
+        for pair in s.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let parts: Vec<&str> = pair.split("=").collect();
+            if parts.len() != 2 {
+                return Err(format!("invalid alias {}", pair).into());
+            }
+            m.insert(parts[0].trim().to_lowercase(), parts[1].trim().to_owned());
+        }
+        Ok(Aliases(m))
+    }
+}
+impl Aliases {
+    pub fn get(&self, alias: &str) -> Option<&String> {
+        self.0.get(alias)
+    }
+}
+
+/// Whether the API keys are checked: `off`, `optional` to limit the clients
+/// without the key by their address, or `required`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    Off,
+    Optional,
+    Required,
+}
+impl std::str::FromStr for AuthMode {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" | "" => Ok(AuthMode::Off),
+            "optional" => Ok(AuthMode::Optional),
+            "required" => Ok(AuthMode::Required),
+            _ => Err(format!("unknown auth mode {}", s).into()),
+        }
+    }
+}
+
+/// Origins allowed to call the API from the browser, `*` for any origin
+#[derive(Debug, Clone, Default)]
+pub struct Origins(Vec<String>);
+impl std::str::FromStr for Origins {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Origins(
+            s.split(",")
+                .map(|x| x.trim().trim_end_matches('/').to_owned())
+                .filter(|x| !x.is_empty())
+                .collect(),
+        ))
+    }
+}
+impl Origins {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// whether the allowed origin in the response depends on the origin of the request
+    pub fn varies(&self) -> bool {
+        !self.is_empty() && self.origin() != Origin::Any
+    }
+    pub fn origin(&self) -> Origin {
+        if self.0.iter().any(|x| x == "*") {
+            return Origin::Any;
+        }
+        match self.0.as_slice() {
+            [x] => Origin::Exact(x.clone()),
+            _ => Origin::List(self.0.clone()),
+        }
+    }
+}
+
+/// Addresses of the reverse proxies whose `X-Forwarded-For` is trusted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Proxies(Vec<std::net::IpAddr>);
+impl std::str::FromStr for Proxies {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut out = vec![];
+        for x in s.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match x.parse() {
+                Ok(ip) => out.push(ip),
+                Err(e) => return Err(format!("invalid proxy address {}: {}", x, e).into()),
+            }
+        }
+        Ok(Proxies(out))
+    }
+}
+impl Proxies {
+    pub fn contains(&self, ip: &std::net::IpAddr) -> bool {
+        self.0.contains(ip)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -70,6 +232,22 @@ impl Currencies {
     pub fn iter(&self) -> std::slice::Iter<'_, std::string::String> {
         self.0.iter()
     }
+    pub fn contains(&self, currency: &str) -> bool {
+        self.0.iter().any(|x| x == currency)
+    }
+    /// currencies from the comma separated list, each of them should be configured
+    pub fn select(&self, list: &str) -> Result<Currencies, String> {
+        let mut out: Vec<String> = vec![];
+        for currency in list.split(",").map(|x| x.trim().to_lowercase()) {
+            if !self.contains(&currency) {
+                return Err(format!("unknown currency {}", currency));
+            }
+            if !out.contains(&currency) {
+                out.push(currency);
+            }
+        }
+        Ok(Currencies(out))
+    }
     pub fn as_map(&self) -> HashMap<String, f64> {
         self.0
             .iter()
@@ -79,13 +257,84 @@ impl Currencies {
     }
 }
 
+/// Series stored for each currency of the market
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Price,
+    MarketCap,
+    Volume,
+}
+impl Field {
+    /// suffix of the database column and of the provider response keys
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Field::Price => "",
+            Field::MarketCap => "_market_cap",
+            Field::Volume => "_24h_vol",
+        }
+    }
+    pub fn column(&self, currency: &str) -> String {
+        format!("{}{}", currency, self.suffix())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fields(Vec<Field>);
+impl std::str::FromStr for Fields {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut out = vec![];
+        for x in s.split(",").map(|x| x.trim()) {
+            let field = match x {
+                "price" => Field::Price,
+                "market_cap" => Field::MarketCap,
+                "volume" | "total_volume" => Field::Volume,
+                _ => return Err(format!("unknown field {}", x).into()),
+            };
+            if !out.contains(&field) {
+                out.push(field);
+            }
+        }
+        Ok(Fields(out))
+    }
+}
+impl Default for Fields {
+    fn default() -> Self {
+        Fields(vec![Field::Price])
+    }
+}
+impl Fields {
+    pub fn iter(&self) -> std::slice::Iter<'_, Field> {
+        self.0.iter()
+    }
+    pub fn contains(&self, field: Field) -> bool {
+        self.0.contains(&field)
+    }
+    /// database columns of the fields for each currency
+    pub fn columns(&self, currencies: &Currencies) -> Vec<String> {
+        let mut out = vec![];
+        for currency in currencies.iter() {
+            for field in self.iter() {
+                out.push(field.column(currency));
+            }
+        }
+        out
+    }
+}
+
 #[derive(Clone)]
 pub struct State {
     pub db_pool: sqlx::Pool<sqlx::postgres::Postgres>,
     pub markets: Markets,
+    pub aliases: Aliases,
     pub currencies: Currencies,
+    pub max_period_days: i64,
+    /// seconds between the checks of the live stream
+    pub stream_interval: u64,
 }
 
+use tide::http::headers::HeaderValue;
+use tide::security::{CorsMiddleware, Origin};
 use tide::{Middleware, Next, Request};
 
 // This is an example of middleware that keeps its own state and could
@@ -103,6 +352,171 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for LogMiddleware {
     }
 }
 
+/// response headers readable by the browser clients
+const EXPOSE_HEADERS: &str = "etag, link, x-request-id, retry-after, x-approximate, \
+    x-ratelimit-limit, x-ratelimit-remaining, x-ratelimit-reset";
+
+/// description of the header of the local days without hourly prices
+const APPROXIMATE: &str = "`daily` when the hour of the local day is not stored, \
+    the prices are of the UTC day of the same date";
+
+/// routes of the API together with their OpenAPI document
+fn routes(app: &mut tide::Server<State>, auth: AuthMode) -> anyhow::Result<()> {
+    let mut routes = openapi::Routes::new(app, auth);
+    routes
+        .get(
+            "/metrics",
+            "Prometheus gauges of the current prices",
+            api::metrics,
+        )
+        .query(&["fields"])
+        .text();
+    routes
+        .get("/api/health", "Health check", api::health)
+        .returns::<HashMap<String, String>>();
+    routes
+        .get(
+            "/api/current",
+            "Current prices of the markets",
+            api::current,
+        )
+        .query(&["currencies"])
+        .returns::<api::CurrentMarkets>();
+    routes
+        .get(
+            "/api/markets",
+            "Markets with their stored history",
+            api::markets,
+        )
+        .returns::<Vec<api::MarketInfo>>();
+    routes
+        .get(
+            "/api/markets/search",
+            "Search the coins of the provider",
+            api::search,
+        )
+        .query(&["q"])
+        .param("limit", "maximum number of results, 25 by default")
+        .returns::<Vec<resolve::SearchResult>>();
+    routes
+        .get(
+            "/api/convert",
+            "Convert the amount between markets and fiat",
+            api::convert,
+        )
+        .query(&["amount", "at"])
+        .param("from", "asset to convert from: market or fiat currency")
+        .param("to", "asset to convert to: market or fiat currency")
+        .returns::<api::ConvertResponse>();
+    routes
+        .post("/api/batch", "Prices of many markets and days", api::batch)
+        .accepts::<api::BatchRequest>()
+        .returns::<api::BatchResponse>();
+    routes
+        .get(
+            "/api/at/:date",
+            "Prices of many markets for the day",
+            api::multi_history,
+        )
+        .query(&["markets", "currencies", "fields", "tz"])
+        .returns::<api::HistoryResponse>()
+        .header(api::APPROXIMATE_HEADER, APPROXIMATE);
+    routes
+        .get(
+            "/api/from/:from/to/:to",
+            "Prices of many markets for the period",
+            api::multi_period,
+        )
+        .query(&["markets", "currencies", "fields"])
+        .returns::<BTreeMap<String, api::PeriodPrices>>();
+    routes
+        .get(
+            "/api/:market/from/:from/to/:to",
+            "Prices of the market for the period",
+            api::period,
+        )
+        .query(&[
+            "currencies",
+            "fields",
+            "format",
+            "interval",
+            "limit",
+            "cursor",
+            "stream",
+        ])
+        .returns::<api::PeriodPrices>()
+        .or_returns::<api::PeriodOhlc>()
+        .tables()
+        .header("link", "next page of the period as `<url>; rel=\"next\"`");
+    routes
+        .get(
+            "/api/:market/at/:date",
+            "Prices of the market for the day",
+            api::history,
+        )
+        .query(&["currencies", "fields", "format", "tz"])
+        .returns::<api::HistoryResponse>()
+        .tables()
+        .header(api::APPROXIMATE_HEADER, APPROXIMATE);
+    routes
+        .get(
+            "/api/:market/at-ts/:unix",
+            "Prices of the market at the instant",
+            api::history_ts,
+        )
+        .query(&["currencies", "mode"])
+        .returns::<api::InstantResponse>();
+    routes
+        .get(
+            "/api/:market/stats",
+            "Statistics of the market for the period",
+            api::stats,
+        )
+        .query(&["from", "to", "currency"])
+        .returns::<stats::Stats>();
+    routes
+        .get(
+            "/api/:market/in/:base/from/:from/to/:to",
+            "Cross rates for the period",
+            api::cross_period,
+        )
+        .query(&["pivot"])
+        .returns::<api::PeriodPrices>();
+    routes
+        .get(
+            "/api/:market/in/:base/at/:date",
+            "Cross rate for the day",
+            api::cross_history,
+        )
+        .query(&["pivot"])
+        .returns::<api::HistoryResponse>();
+    routes
+        .get(
+            "/api/token/:chain/:address/at/:date",
+            "Prices of the token for the day",
+            api::token_history,
+        )
+        .query(&["currencies", "fields", "format", "tz"])
+        .returns::<api::HistoryResponse>()
+        .tables()
+        .header(api::APPROXIMATE_HEADER, APPROXIMATE);
+    routes
+        .get(
+            "/api/stream",
+            "Server-sent events of the current prices",
+            live::stream,
+        )
+        .query(&["markets", "currencies"])
+        .returns::<live::Update>()
+        .events();
+    routes.get(
+        "/api/ws",
+        "WebSocket subscriptions to the live prices and replay of the history",
+        ws::connect,
+    );
+    routes.serve("/api/openapi.json")
+}
+
 #[async_std::main]
 async fn main() -> Result<(), anyhow::Error> {
     let args = match args::parse() {
@@ -112,6 +526,9 @@ async fn main() -> Result<(), anyhow::Error> {
         }
     };
 
+    let coins_loaded = resolve::load().await;
+    let markets = resolve::configured(&args.markets, &args.aliases)?;
+
     let pool = sqlx::postgres::PgPoolOptions::new()
         .max_connections(args.database_conn)
         .connect_timeout(std::time::Duration::from_secs(3))
@@ -128,26 +545,72 @@ async fn main() -> Result<(), anyhow::Error> {
         }
     };
 
-    exporter::init(&mut conn, &args.markets, &args.currencies).await?;
+    exporter::init(&mut conn, &markets, &args.currencies).await?;
     if args.index > 0 {
         let no_gaps = args.index > 1;
-        exporter::update_history(&mut conn, &args.markets, &args.currencies, no_gaps).await?;
+        exporter::update_history(&mut conn, &markets, &args.currencies, no_gaps).await?;
+        exporter::backfill_fields(&mut conn, &markets, &args.currencies).await?;
+        exporter::update_hourly(&mut conn, &markets, &args.currencies).await?;
     }
     if args.server > 0 {
+        let auth = if args.auth != AuthMode::Off {
+            let keys = auth::load(&mut conn, &args.api_keys_file, args.rate_limit).await?;
+            let auth = auth::AuthMiddleware::new(
+                args.auth,
+                args.rate_limit,
+                keys,
+                args.trusted_proxies.clone(),
+            );
+            let reload = args.api_keys_file.is_none();
+            async_std::task::spawn(auth::maintain(auth.clone(), pool.clone(), reload));
+            Some(auth)
+        } else {
+            None
+        };
+        async_std::task::spawn(resolve::refresh(coins_loaded));
+        if args.index > 0 {
+            async_std::task::spawn(exporter::schedule(
+                pool.clone(),
+                markets.clone(),
+                args.currencies.clone(),
+            ));
+        }
         let state = State {
             db_pool: pool,
-            markets: args.markets.clone(),
+            markets: markets.clone(),
+            aliases: args.aliases.clone(),
             currencies: args.currencies.clone(),
+            max_period_days: args.max_period_days,
+            stream_interval: args.stream_interval,
         };
         info!("Starting HTTP server {}", &args.addr);
         let mut app = tide::with_state(state);
         app.with(LogMiddleware {});
         app.with(telemetry::TraceMiddleware::new());
-        app.at("/metrics").get(api::metrics);
-        app.at("/api/health").get(api::health);
-        app.at("/api/current").get(api::current);
-        app.at("/api/:market/from/:from/to/:to").get(api::period);
-        app.at("/api/:market/at/:date").get(api::history);
+        app.with(error::ErrorMiddleware);
+        if !args.cors_origins.is_empty() {
+            app.with(
+                CorsMiddleware::new()
+                    .allow_origin(args.cors_origins.origin())
+                    .allow_methods(args.cors_methods.clone())
+                    .allow_headers(args.cors_headers.clone())
+                    .max_age(args.cors_max_age.clone())
+                    .expose_headers(EXPOSE_HEADERS.parse::<HeaderValue>().unwrap()),
+            );
+        }
+        if let Some(auth) = auth {
+            app.with(auth);
+        }
+        app.with(compress::CompressMiddleware::new(args.compress_min_bytes));
+        let mut cache = cache::CacheMiddleware::new();
+        if args.cors_origins.varies() {
+            cache = cache.vary("origin");
+        }
+        if args.auth != AuthMode::Off {
+            cache = cache.private();
+        }
+        app.with(cache);
+        routes(&mut app, args.auth)?;
         app.listen(&args.addr).await?;
     }
     Ok(())
@@ -1,21 +1,37 @@
+use crate::{Currencies, Field, Fields};
 use prometheus::{Encoder, Gauge, Opts, Registry, TextEncoder};
 use std::collections::HashMap;
 
 pub type CurrentMarkets = HashMap<String, HashMap<String, f64>>;
 
-pub fn output(src: CurrentMarkets) -> String {
+fn metric_name(field: &Field) -> &'static str {
+    match field {
+        Field::Price => "price",
+        Field::MarketCap => "market_cap",
+        Field::Volume => "volume_24h",
+    }
+}
+
+pub fn output(src: CurrentMarkets, currencies: &Currencies, fields: &Fields) -> String {
     let encoder = TextEncoder::new();
     let labels = HashMap::new();
     let sr = Registry::new_custom(Some("fiatprices".to_string()), Some(labels)).unwrap();
 
     for (market_name, market) in src {
-        for (currency, value) in market {
-            let gauge_opts = Opts::new("price", "price")
-                .const_label("market", market_name.as_str())
-                .const_label("currency", currency.as_str());
-            let gauge = Gauge::with_opts(gauge_opts).unwrap();
-            gauge.set(value);
-            sr.register(Box::new(gauge.clone())).unwrap();
+        for currency in currencies.iter() {
+            for field in fields.iter() {
+                let value = match market.get(&field.column(currency)) {
+                    Some(x) => *x,
+                    None => continue,
+                };
+                let name = metric_name(field);
+                let gauge_opts = Opts::new(name, name)
+                    .const_label("market", market_name.as_str())
+                    .const_label("currency", currency.as_str());
+                let gauge = Gauge::with_opts(gauge_opts).unwrap();
+                gauge.set(value);
+                sr.register(Box::new(gauge.clone())).unwrap();
+            }
         }
     }
 
@@ -0,0 +1,527 @@
+use crate::auth::PUBLIC_PATHS;
+use crate::error::ErrorBody;
+use crate::{AuthMode, State};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use tide::http::mime;
+use tide::{Endpoint, Response, Server};
+
+/// parameters in the path of the routes
+const PATH_PARAMS: &[(&str, &str)] = &[
+    ("market", "market id, symbol or alias"),
+    (
+        "base",
+        "market id, symbol or alias to express the prices in",
+    ),
+    ("date", "day as YYYY-MM-DD"),
+    ("from", "first day of the period as YYYY-MM-DD"),
+    ("to", "last day of the period as YYYY-MM-DD"),
+    ("unix", "instant as the unix timestamp in seconds"),
+    ("chain", "chain of the token contract"),
+    ("address", "address of the token contract"),
+];
+
+/// parameters in the query string of the routes
+const QUERY_PARAMS: &[(&str, &str)] = &[
+    (
+        "currencies",
+        "comma separated fiat currencies, all configured by default",
+    ),
+    (
+        "fields",
+        "comma separated price, market_cap, volume; price by default",
+    ),
+    ("markets", "comma separated market ids, symbols or aliases"),
+    ("tz", "IANA time zone of the day boundaries, UTC by default"),
+    (
+        "format",
+        "json, csv or ndjson, negotiated from Accept header by default",
+    ),
+    ("interval", "week, month or year to aggregate the days"),
+    ("limit", "rows per page, enables pagination"),
+    ("cursor", "first day of the page, taken from the next link"),
+    (
+        "stream",
+        "stream the whole period, lifts the maximum days also of CSV and NDJSON",
+    ),
+    ("mode", "nearest or linear"),
+    ("pivot", "fiat currency of the cross rate"),
+    ("currency", "fiat currency of the series"),
+    ("q", "part of the coin id, symbol or name"),
+    ("amount", "amount to convert, 1 by default"),
+    ("from", "first day of the period as YYYY-MM-DD"),
+    ("to", "last day of the period as YYYY-MM-DD"),
+    ("at", "YYYY-MM-DD, unix timestamp or now"),
+];
+
+/// Route registered in the server and described in the document
+pub struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    query: Vec<(&'static str, String)>,
+    request: Option<fn(&mut SchemaGenerator) -> Schema>,
+    response: Option<fn(&mut SchemaGenerator) -> Schema>,
+    /// other shapes of the response, depending on the parameters
+    alternatives: Vec<fn(&mut SchemaGenerator) -> Schema>,
+    content: &'static str,
+    /// response is also available as CSV and NDJSON
+    tables: bool,
+    headers: Vec<(&'static str, &'static str)>,
+}
+
+impl Operation {
+    /// names of the query parameters, from `QUERY_PARAMS`
+    pub fn query(&mut self, names: &[&'static str]) -> &mut Self {
+        for name in names {
+            self.query.push((name, describe_param(QUERY_PARAMS, name)));
+        }
+        self
+    }
+
+    /// query parameter with the meaning specific to the route
+    pub fn param(&mut self, name: &'static str, description: &str) -> &mut Self {
+        self.query.push((name, description.to_owned()));
+        self
+    }
+
+    /// type of the JSON request body
+    pub fn accepts<T: JsonSchema>(&mut self) -> &mut Self {
+        self.request = Some(SchemaGenerator::subschema_for::<T>);
+        self
+    }
+
+    /// type of the JSON response body
+    pub fn returns<T: JsonSchema>(&mut self) -> &mut Self {
+        self.response = Some(SchemaGenerator::subschema_for::<T>);
+        self
+    }
+
+    /// other type of the JSON response body, the parameters decide which one is returned
+    pub fn or_returns<T: JsonSchema>(&mut self) -> &mut Self {
+        self.alternatives.push(SchemaGenerator::subschema_for::<T>);
+        self
+    }
+
+    /// response is also available as CSV and NDJSON tables, see `stream::negotiate`
+    pub fn tables(&mut self) -> &mut Self {
+        self.tables = true;
+        self
+    }
+
+    /// header of the successful response
+    pub fn header(&mut self, name: &'static str, description: &'static str) -> &mut Self {
+        self.headers.push((name, description));
+        self
+    }
+
+    /// response is plain text
+    pub fn text(&mut self) -> &mut Self {
+        self.content = "text/plain";
+        self
+    }
+
+    /// response is the stream of server-sent events with the returned type as their data
+    pub fn events(&mut self) -> &mut Self {
+        self.content = "text/event-stream";
+        self
+    }
+
+    fn describe(&self, gen: &mut SchemaGenerator, auth: AuthMode) -> Value {
+        let public = auth == AuthMode::Off || PUBLIC_PATHS.contains(&self.path);
+        let mut parameters: Vec<Value> = vec![];
+        for segment in self.path.split('/') {
+            if let Some(name) = segment.strip_prefix(':') {
+                parameters.push(json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "description": describe_param(PATH_PARAMS, name),
+                    "schema": { "type": "string" },
+                }));
+            }
+        }
+        for (name, description) in &self.query {
+            parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": false,
+                "description": description,
+                "schema": { "type": "string" },
+            }));
+        }
+        let mut schema = match self.response {
+            Some(f) => serde_json::to_value(f(gen)).unwrap_or_default(),
+            None => json!({ "type": "string" }),
+        };
+        if !self.alternatives.is_empty() {
+            let mut one_of = vec![schema];
+            for f in &self.alternatives {
+                one_of.push(serde_json::to_value(f(gen)).unwrap_or_default());
+            }
+            schema = json!({ "oneOf": one_of });
+        }
+        let mut content = json!({ self.content: { "schema": schema } });
+        if self.tables {
+            content["text/csv"] = json!({ "schema": { "type": "string" } });
+            content["application/x-ndjson"] = json!({ "schema": { "type": "string" } });
+        }
+        let mut headers = Map::new();
+        for (name, description) in &self.headers {
+            headers.insert(name.to_string(), header(description));
+        }
+        if !public {
+            headers.extend(rate_headers());
+        }
+        let error = serde_json::to_value(gen.subschema_for::<ErrorBody>()).unwrap_or_default();
+        let error_content = json!({ "application/json": { "schema": error } });
+        let mut op = json!({
+            "summary": self.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "OK",
+                    "headers": headers,
+                    "content": content,
+                },
+                "default": {
+                    "description": "Error",
+                    "content": error_content,
+                },
+            },
+        });
+        if public {
+            // listed in the document as open to everyone
+            if auth != AuthMode::Off {
+                op["security"] = json!([]);
+            }
+        } else {
+            op["responses"]["401"] = json!({
+                "description": "API key is missing or unknown",
+                "content": error_content,
+            });
+            let mut headers = rate_headers();
+            headers.insert(
+                "retry-after".to_owned(),
+                header("seconds until the next request is allowed"),
+            );
+            op["responses"]["429"] = json!({
+                "description": "rate limit of the API key or the address is exceeded",
+                "headers": headers,
+                "content": error_content,
+            });
+        }
+        if let Some(f) = self.request {
+            let schema = serde_json::to_value(f(gen)).unwrap_or_default();
+            op["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema } },
+            });
+        }
+        op
+    }
+}
+
+fn header(description: &str) -> Value {
+    json!({ "description": description, "schema": { "type": "string" } })
+}
+
+/// headers of the responses limited by `AuthMiddleware`
+fn rate_headers() -> Map<String, Value> {
+    let mut out = Map::new();
+    out.insert(
+        "x-ratelimit-limit".to_owned(),
+        header("requests per minute"),
+    );
+    out.insert("x-ratelimit-remaining".to_owned(), header("requests left"));
+    out.insert(
+        "x-ratelimit-reset".to_owned(),
+        header("seconds until the limit is fully restored"),
+    );
+    out
+}
+
+/// ways to pass the API key, all of them are accepted
+fn security_schemes() -> Value {
+    json!({
+        "api_key": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+        "bearer": { "type": "http", "scheme": "bearer" },
+        "api_key_query": { "type": "apiKey", "in": "query", "name": "api_key" },
+    })
+}
+
+fn describe_param(params: &[(&str, &str)], name: &str) -> String {
+    match params.iter().find(|(n, _)| *n == name) {
+        Some((_, x)) => x.to_string(),
+        None => String::new(),
+    }
+}
+
+/// tide path `/api/:market` as OpenAPI path `/api/{market}`
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|s| match s.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => s.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Registers the routes of the server together with their description,
+/// so that the document can't miss a route
+pub struct Routes<'a> {
+    app: &'a mut Server<State>,
+    ops: Vec<Operation>,
+    /// how the API keys are checked, described as the security of the routes
+    auth: AuthMode,
+}
+
+impl<'a> Routes<'a> {
+    pub fn new(app: &'a mut Server<State>, auth: AuthMode) -> Self {
+        Self {
+            app,
+            ops: vec![],
+            auth,
+        }
+    }
+
+    fn push(
+        &mut self,
+        method: &'static str,
+        path: &'static str,
+        summary: &'static str,
+    ) -> &mut Operation {
+        self.ops.push(Operation {
+            method,
+            path,
+            summary,
+            query: vec![],
+            request: None,
+            response: None,
+            alternatives: vec![],
+            content: "application/json",
+            tables: false,
+            headers: vec![],
+        });
+        self.ops.last_mut().unwrap()
+    }
+
+    pub fn get(
+        &mut self,
+        path: &'static str,
+        summary: &'static str,
+        ep: impl Endpoint<State>,
+    ) -> &mut Operation {
+        self.app.at(path).get(ep);
+        self.push("get", path, summary)
+    }
+
+    pub fn post(
+        &mut self,
+        path: &'static str,
+        summary: &'static str,
+        ep: impl Endpoint<State>,
+    ) -> &mut Operation {
+        self.app.at(path).post(ep);
+        self.push("post", path, summary)
+    }
+
+    /// OpenAPI 3 document of the registered routes
+    fn document(&self) -> Value {
+        let mut gen = SchemaSettings::openapi3().into_generator();
+        let mut paths = Map::new();
+        for op in &self.ops {
+            let item = paths
+                .entry(openapi_path(op.path))
+                .or_insert_with(|| json!({}));
+            item[op.method] = op.describe(&mut gen, self.auth);
+        }
+        let schemas = serde_json::to_value(gen.take_definitions()).unwrap_or_default();
+        let mut doc = json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": paths,
+            "components": { "schemas": schemas },
+        });
+        if self.auth != AuthMode::Off {
+            let mut security = vec![
+                json!({ "api_key": [] }),
+                json!({ "bearer": [] }),
+                json!({ "api_key_query": [] }),
+            ];
+            // clients without the key are served with the limit of their address
+            if self.auth == AuthMode::Optional {
+                security.push(json!({}));
+            }
+            doc["components"]["securitySchemes"] = security_schemes();
+            doc["security"] = json!(security);
+        }
+        doc
+    }
+
+    /// serves the document of all the routes registered before, including itself
+    pub fn serve(mut self, path: &'static str) -> anyhow::Result<()> {
+        self.push("get", path, "OpenAPI document of the service")
+            .returns::<Value>();
+        let doc = serde_json::to_string(&self.document())?;
+        self.app.at(path).get(move |_| {
+            let doc = doc.clone();
+            async move {
+                let mut res = Response::new(200);
+                res.set_body(doc);
+                res.set_content_type(mime::JSON);
+                Ok(res)
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AuthMode, State};
+    use serde_json::Value;
+    use tide::http::{Method, Request, Response, Url};
+
+    /// document served by the app with the routes of `main`
+    async fn document(auth: AuthMode) -> Value {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/fiatprices")
+            .unwrap();
+        let state = State {
+            db_pool: pool,
+            markets: "bitcoin,ethereum".parse().unwrap(),
+            aliases: "btc=bitcoin".parse().unwrap(),
+            currencies: "usd,eur".parse().unwrap(),
+            max_period_days: 366,
+            stream_interval: 15,
+        };
+        let mut app = tide::with_state(state);
+        crate::routes(&mut app, auth).unwrap();
+        let url = Url::parse("http://localhost/api/openapi.json").unwrap();
+        let mut res: Response = app.respond(Request::new(Method::Get, url)).await.unwrap();
+        assert_eq!(res.status(), 200);
+        res.body_json().await.unwrap()
+    }
+
+    /// all `$ref` values of the document
+    fn refs(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::Object(m) => {
+                for (k, v) in m {
+                    match v {
+                        Value::String(x) if k == "$ref" => out.push(x.clone()),
+                        _ => refs(v, out),
+                    }
+                }
+            }
+            Value::Array(list) => list.iter().for_each(|v| refs(v, out)),
+            _ => {}
+        }
+    }
+
+    #[async_std::test]
+    async fn paths_are_described() {
+        let doc = document(AuthMode::Off).await;
+        let paths = doc["paths"].as_object().unwrap();
+        for (path, method) in &[
+            ("/metrics", "get"),
+            ("/api/current", "get"),
+            ("/api/markets", "get"),
+            ("/api/batch", "post"),
+            ("/api/{market}/at/{date}", "get"),
+            ("/api/{market}/from/{from}/to/{to}", "get"),
+            ("/api/{market}/at-ts/{unix}", "get"),
+            ("/api/token/{chain}/{address}/at/{date}", "get"),
+            ("/api/openapi.json", "get"),
+        ] {
+            assert!(paths[*path][*method].is_object(), "{} {}", method, path);
+        }
+        for (path, item) in paths {
+            for (method, op) in item.as_object().unwrap() {
+                let params = op["parameters"].as_array().unwrap();
+                for segment in path.split('/') {
+                    if let Some(name) = segment.strip_prefix('{') {
+                        let name = name.trim_end_matches('}');
+                        let declared = params
+                            .iter()
+                            .any(|p| p["name"] == name && p["in"] == "path");
+                        assert!(declared, "{} {} misses {}", method, path, name);
+                    }
+                }
+                for p in params {
+                    let described = p["description"].as_str().unwrap_or("");
+                    assert!(!described.is_empty(), "{} {} {}", method, path, p["name"]);
+                }
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn schemas_are_defined() {
+        let doc = document(AuthMode::Off).await;
+        let schemas = doc["components"]["schemas"].as_object().unwrap();
+        for name in &[
+            "HistoryResponse",
+            "ErrorBody",
+            "Ohlc",
+            "BatchRequest",
+            "BatchResponse",
+            "ConvertResponse",
+            "Stats",
+        ] {
+            assert!(schemas.contains_key(*name), "{}", name);
+        }
+        let mut found = vec![];
+        refs(&doc, &mut found);
+        assert!(!found.is_empty());
+        for r in found {
+            let name = r.trim_start_matches("#/components/schemas/");
+            assert!(schemas.contains_key(name), "{} is not defined", r);
+        }
+    }
+
+    #[async_std::test]
+    async fn period_formats() {
+        let doc = document(AuthMode::Off).await;
+        let ok = &doc["paths"]["/api/{market}/from/{from}/to/{to}"]["get"]["responses"]["200"];
+        assert_eq!(
+            ok["content"]["application/json"]["schema"]["oneOf"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(ok["content"]["text/csv"].is_object());
+        assert!(ok["content"]["application/x-ndjson"].is_object());
+        assert!(ok["headers"]["link"].is_object());
+    }
+
+    #[async_std::test]
+    async fn security_follows_auth_mode() {
+        let doc = document(AuthMode::Off).await;
+        assert!(doc.get("security").is_none());
+
+        let doc = document(AuthMode::Required).await;
+        assert!(doc["components"]["securitySchemes"]["api_key"].is_object());
+        let security = doc["security"].as_array().unwrap();
+        assert!(!security.iter().any(|x| x == &serde_json::json!({})));
+        assert_eq!(
+            doc["paths"]["/api/health"]["get"]["security"],
+            serde_json::json!([])
+        );
+        let current = &doc["paths"]["/api/current"]["get"]["responses"];
+        assert!(current["429"]["headers"]["retry-after"].is_object());
+        assert!(current["200"]["headers"]["x-ratelimit-limit"].is_object());
+
+        let doc = document(AuthMode::Optional).await;
+        let security = doc["security"].as_array().unwrap();
+        assert!(security.iter().any(|x| x == &serde_json::json!({})));
+    }
+}
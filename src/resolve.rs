@@ -0,0 +1,201 @@
+use crate::fetch::{self, Coin};
+use crate::{Aliases, Market, Markets};
+use async_std::task;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const MAX_SUGGESTIONS: usize = 10;
+/// seconds between the downloads of the coin list
+const REFRESH_SECS: u64 = 86400;
+/// seconds before the failed download is tried again
+const RETRY_SECS: u64 = 300;
+
+lazy_static! {
+    /// provider coin list, empty until it is downloaded
+    static ref COINS: RwLock<Arc<Vec<Coin>>> = RwLock::new(Arc::new(vec![]));
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveError {
+    /// nothing in the configuration or provider coin list matches the query
+    Unknown(String),
+    /// the query matches more than one configured market
+    Ambiguous(String, Vec<String>),
+    /// the query is a known coin, but it is not indexed by this service
+    NotConfigured(String, Vec<String>),
+}
+
+impl ResolveError {
+    pub fn suggestions(&self) -> Vec<String> {
+        match self {
+            Self::Unknown(_) => vec![],
+            Self::Ambiguous(_, x) => x.clone(),
+            Self::NotConfigured(_, x) => x.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unknown(q) => write!(f, "unknown market '{}'", q),
+            Self::Ambiguous(q, _) => write!(f, "ambiguous market '{}'", q),
+            Self::NotConfigured(q, _) => write!(f, "market '{}' is not configured", q),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// downloads the coin list outside of the async executor, returns whether it succeeded.
+/// the previous list is kept on failures
+pub async fn load() -> bool {
+    match task::spawn_blocking(fetch::coins).await {
+        Ok(list) => {
+            info!("{} coins in the provider list", list.len());
+            *COINS.write().unwrap() = Arc::new(list);
+            true
+        }
+        Err(e) => {
+            warn!("coin list is not available: {}", e);
+            false
+        }
+    }
+}
+
+/// keeps the coin list fresh, failed downloads are retried sooner
+pub async fn refresh(mut loaded: bool) {
+    loop {
+        let secs = if loaded { REFRESH_SECS } else { RETRY_SECS };
+        task::sleep(Duration::from_secs(secs)).await;
+        loaded = load().await;
+    }
+}
+
+/// last downloaded coin list, requests never wait for the provider
+fn coins() -> Arc<Vec<Coin>> {
+    COINS.read().unwrap().clone()
+}
+
+/// coins from the provider list which are matching query by id, symbol or name
+fn candidates<'a>(list: &'a [Coin], q: &str) -> Vec<&'a Coin> {
+    list.iter()
+        .filter(|c| c.id == q || c.symbol.to_lowercase() == q || c.name.to_lowercase() == q)
+        .collect()
+}
+
+/// resolves market id, ticker or alias into one of the configured markets
+pub fn market(markets: &Markets, aliases: &Aliases, query: &str) -> Result<Market, ResolveError> {
+    let q = query.trim().to_lowercase();
+    if let Some(m) = markets.get(&q) {
+        return Ok(m.clone());
+    }
+    if let Some(id) = aliases.get(&q) {
+        return match markets.get(id) {
+            Some(m) => Ok(m.clone()),
+            None => Err(ResolveError::NotConfigured(q, vec![id.clone()])),
+        };
+    }
+    let list = coins();
+    let found = candidates(&list, &q);
+    let configured: Vec<&Market> = found.iter().filter_map(|c| markets.get(&c.id)).collect();
+    match configured.len() {
+        1 => Ok(configured[0].clone()),
+        0 if found.is_empty() => Err(ResolveError::Unknown(q)),
+        0 => Err(ResolveError::NotConfigured(
+            q,
+            found
+                .iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|c| c.id.clone())
+                .collect(),
+        )),
+        _ => Err(ResolveError::Ambiguous(
+            q,
+            configured.iter().map(|m| m.name.clone()).collect(),
+        )),
+    }
+}
+
+/// replaces tickers and aliases in the configured markets with canonical coin ids
+pub fn configured(markets: &Markets, aliases: &Aliases) -> anyhow::Result<Markets> {
+    let list = coins();
+    let mut out: Vec<Market> = vec![];
+    for market in markets.iter() {
+        let mut m = market.clone();
+        if m.contract.is_some() {
+            // tokens are identified by their address
+        } else if let Some(id) = aliases.get(&m.name) {
+            m.name = id.clone();
+        } else if !list.is_empty() && !list.iter().any(|c| c.id == m.name) {
+            let found = candidates(&list, &m.name);
+            match found.len() {
+                0 => warn!("market {} is not in the coin list", m.name),
+                1 => m.name = found[0].id.clone(),
+                _ => {
+                    let ids: Vec<String> = found.iter().map(|c| c.id.clone()).collect();
+                    return Err(anyhow::anyhow!(
+                        "ambiguous market {}, use one of: {}",
+                        m.name,
+                        ids.join(",")
+                    ));
+                }
+            }
+        }
+        out.push(m);
+    }
+    Ok(Markets(out))
+}
+
+#[derive(Clone, Debug, Serialize, schemars::JsonSchema)]
+pub struct SearchResult {
+    pub id: String,
+    pub symbol: String,
+    pub name: String,
+    pub configured: bool,
+}
+
+/// searches provider coin list, best matches first
+pub fn search(markets: &Markets, query: &str, limit: usize) -> Vec<SearchResult> {
+    let q = query.trim().to_lowercase();
+    let list = coins();
+    let mut found: Vec<(u8, &Coin)> = list
+        .iter()
+        .filter_map(|c| {
+            let symbol = c.symbol.to_lowercase();
+            let name = c.name.to_lowercase();
+            let rank = if c.id == q {
+                0
+            } else if symbol == q {
+                1
+            } else if name == q {
+                2
+            } else if c.id.starts_with(&q) || name.starts_with(&q) {
+                3
+            } else if c.id.contains(&q) || name.contains(&q) {
+                4
+            } else {
+                return None;
+            };
+            Some((rank, c))
+        })
+        .collect();
+    found.sort_by(|a, b| {
+        let a_missing = markets.get(&a.1.id).is_none();
+        let b_missing = markets.get(&b.1.id).is_none();
+        (a.0, a_missing, &a.1.id).cmp(&(b.0, b_missing, &b.1.id))
+    });
+    found
+        .iter()
+        .take(limit)
+        .map(|(_, c)| SearchResult {
+            id: c.id.clone(),
+            symbol: c.symbol.clone(),
+            name: c.name.clone(),
+            configured: markets.get(&c.id).is_some(),
+        })
+        .collect()
+}
@@ -0,0 +1,222 @@
+use chrono::NaiveDate;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Value of the series with the date it was observed
+#[derive(Clone, Debug, Serialize, JsonSchema, PartialEq)]
+pub struct Point {
+    pub date: String,
+    pub value: f64,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema, PartialEq)]
+pub struct Drawdown {
+    /// largest decline from the peak, as a fraction of the peak
+    pub value: f64,
+    pub peak: String,
+    pub trough: String,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub first: Point,
+    pub last: Point,
+    pub min: Point,
+    pub max: Point,
+    pub mean: f64,
+    pub median: f64,
+    /// relative change from the first to the last value
+    pub total_return: f64,
+    /// standard deviation of daily log returns
+    pub volatility: f64,
+    pub volatility_annualized: f64,
+    pub max_drawdown: Drawdown,
+}
+
+/// whether the day `b` follows the day `a`, both as `YYYY-MM-DD`
+fn next_day(a: &str, b: &str) -> bool {
+    let parse = |x: &str| NaiveDate::parse_from_str(x, "%Y-%m-%d").ok();
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => (b - a).num_days() == 1,
+        _ => false,
+    }
+}
+
+/// statistics of the daily series ordered by date, none when there are no values.
+/// returns are taken between the consecutive days only, the change across a gap is not daily
+pub fn compute(series: &[(String, f64)]) -> Option<Stats> {
+    let point = |(date, value): &(String, f64)| Point {
+        date: date.clone(),
+        value: *value,
+    };
+    let first = point(series.first()?);
+    let last = point(series.last()?);
+    let count = series.len();
+
+    let mut min = first.clone();
+    let mut max = first.clone();
+    let mut sum = 0.0;
+    for (date, value) in series {
+        sum += value;
+        if *value < min.value {
+            min = point(&(date.clone(), *value));
+        }
+        if *value > max.value {
+            max = point(&(date.clone(), *value));
+        }
+    }
+    let mean = sum / count as f64;
+
+    let mut sorted: Vec<f64> = series.iter().map(|(_, v)| *v).collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = if count % 2 == 1 {
+        sorted[count / 2]
+    } else {
+        (sorted[count / 2 - 1] + sorted[count / 2]) / 2.0
+    };
+
+    let returns: Vec<f64> = series
+        .windows(2)
+        .filter(|w| next_day(&w[0].0, &w[1].0))
+        .map(|w| (w[1].1 / w[0].1).ln())
+        .collect();
+    let volatility = if returns.len() > 1 {
+        let n = returns.len() as f64;
+        let avg = returns.iter().sum::<f64>() / n;
+        let var = returns.iter().map(|r| (r - avg).powi(2)).sum::<f64>() / (n - 1.0);
+        var.sqrt()
+    } else {
+        0.0
+    };
+
+    let mut peak = first.clone();
+    let mut max_drawdown = Drawdown {
+        value: 0.0,
+        peak: first.date.clone(),
+        trough: first.date.clone(),
+    };
+    for (date, value) in series {
+        if *value > peak.value {
+            peak = point(&(date.clone(), *value));
+        }
+        let drawdown = (peak.value - value) / peak.value;
+        if drawdown > max_drawdown.value {
+            max_drawdown = Drawdown {
+                value: drawdown,
+                peak: peak.date.clone(),
+                trough: date.clone(),
+            };
+        }
+    }
+
+    Some(Stats {
+        count,
+        total_return: (last.value - first.value) / first.value,
+        first,
+        last,
+        min,
+        max,
+        mean,
+        median,
+        volatility,
+        volatility_annualized: volatility * 365f64.sqrt(),
+        max_drawdown,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(values: &[(&str, f64)]) -> Vec<(String, f64)> {
+        values.iter().map(|(d, v)| (d.to_string(), *v)).collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn empty_series() {
+        assert_eq!(compute(&[]), None);
+    }
+
+    #[test]
+    fn median_of_odd_and_even_count() {
+        let odd = series(&[
+            ("2021-01-01", 3.0),
+            ("2021-01-02", 1.0),
+            ("2021-01-03", 2.0),
+        ]);
+        assert!(close(compute(&odd).unwrap().median, 2.0));
+        let even = series(&[
+            ("2021-01-01", 1.0),
+            ("2021-01-02", 4.0),
+            ("2021-01-03", 2.0),
+            ("2021-01-04", 3.0),
+        ]);
+        assert!(close(compute(&even).unwrap().median, 2.5));
+    }
+
+    #[test]
+    fn sample_volatility() {
+        // returns are ln 2 and -ln 2, their sample variance is 2 ln² 2
+        let s = series(&[
+            ("2021-01-01", 100.0),
+            ("2021-01-02", 200.0),
+            ("2021-01-03", 100.0),
+        ]);
+        let stats = compute(&s).unwrap();
+        let expected = 2f64.sqrt() * 2f64.ln();
+        assert!(close(stats.volatility, expected));
+        assert!(close(stats.volatility_annualized, expected * 365f64.sqrt()));
+        assert!(close(stats.total_return, 0.0));
+        assert!(close(stats.mean, 400.0 / 3.0));
+    }
+
+    #[test]
+    fn single_return_has_no_volatility() {
+        let s = series(&[("2021-01-01", 100.0), ("2021-01-02", 200.0)]);
+        assert!(close(compute(&s).unwrap().volatility, 0.0));
+    }
+
+    #[test]
+    fn returns_across_gaps_are_skipped() {
+        // 2021-01-03 is missing, the change from 200 to 400 is not a daily return
+        let s = series(&[
+            ("2021-01-01", 100.0),
+            ("2021-01-02", 200.0),
+            ("2021-01-04", 400.0),
+            ("2021-01-05", 200.0),
+        ]);
+        let stats = compute(&s).unwrap();
+        assert!(close(stats.volatility, 2f64.sqrt() * 2f64.ln()));
+    }
+
+    #[test]
+    fn max_drawdown_dates() {
+        let s = series(&[
+            ("2021-01-01", 100.0),
+            ("2021-01-02", 150.0),
+            ("2021-01-03", 120.0),
+            ("2021-01-04", 160.0),
+            ("2021-01-05", 80.0),
+            ("2021-01-06", 90.0),
+        ]);
+        let stats = compute(&s).unwrap();
+        assert!(close(stats.max_drawdown.value, 0.5));
+        assert_eq!(stats.max_drawdown.peak, "2021-01-04");
+        assert_eq!(stats.max_drawdown.trough, "2021-01-05");
+        assert_eq!(stats.min.date, "2021-01-05");
+        assert_eq!(stats.max.date, "2021-01-04");
+    }
+
+    #[test]
+    fn no_drawdown_when_rising() {
+        let s = series(&[("2021-01-01", 1.0), ("2021-01-02", 2.0)]);
+        let stats = compute(&s).unwrap();
+        assert!(close(stats.max_drawdown.value, 0.0));
+        assert_eq!(stats.max_drawdown.peak, "2021-01-01");
+    }
+}
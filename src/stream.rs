@@ -0,0 +1,202 @@
+use crate::db::PriceRow;
+use anyhow::Result;
+use async_std::channel::Receiver;
+use async_std::io::{BufRead, Read};
+use async_std::stream::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tide::http::mime::{self, Mime};
+use tide::{Body, Request};
+use tracing::warn;
+
+/// Output format of the price tables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    pub fn mime(&self) -> Mime {
+        match self {
+            Format::Json => mime::JSON,
+            Format::Csv => "text/csv; charset=utf-8".parse().unwrap(),
+            Format::Ndjson => "application/x-ndjson".parse().unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FormatQuery {
+    format: Option<String>,
+}
+
+/// format from `format=` parameter, or from the `Accept` header, JSON by default
+pub fn negotiate<State>(req: &Request<State>) -> std::result::Result<Format, String> {
+    let query: FormatQuery = match req.query() {
+        Ok(x) => x,
+        Err(e) => return Err(e.to_string()),
+    };
+    if let Some(format) = query.format {
+        return match format.as_str() {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(format!("unknown format {}", format)),
+        };
+    }
+    let accept = match req.header("accept") {
+        Some(x) => x.as_str().to_lowercase(),
+        None => return Ok(Format::Json),
+    };
+    if accept.contains("text/csv") {
+        Ok(Format::Csv)
+    } else if accept.contains("ndjson") {
+        Ok(Format::Ndjson)
+    } else {
+        Ok(Format::Json)
+    }
+}
+
+/// beginning of the output: CSV header or opening of JSON object
+pub fn header(format: Format, columns: &[String]) -> String {
+    match format {
+        Format::Csv => format!("date,{}\n", columns.join(",")),
+        Format::Json => "{".to_owned(),
+        Format::Ndjson => String::new(),
+    }
+}
+
+/// end of the output, only JSON object needs closing
+pub fn footer(format: Format) -> String {
+    match format {
+        Format::Json => "}".to_owned(),
+        _ => String::new(),
+    }
+}
+
+/// one row of the output, values missing in the row are left empty.
+/// JSON rows are the entries of the object keyed by date, without separators
+pub fn line(format: Format, columns: &[String], row: &PriceRow) -> String {
+    let (date, prices) = row;
+    match format {
+        Format::Json => format!(
+            "{}:{}",
+            serde_json::Value::from(date.as_str()),
+            serde_json::to_string(prices).unwrap_or("{}".to_owned())
+        ),
+        Format::Csv => {
+            let values: Vec<String> = columns
+                .iter()
+                .map(|c| match prices.get(c) {
+                    Some(x) => x.to_string(),
+                    None => String::new(),
+                })
+                .collect();
+            format!("{},{}\n", date, values.join(","))
+        }
+        Format::Ndjson => {
+            let mut m = serde_json::Map::new();
+            m.insert("date".to_owned(), date.clone().into());
+            for c in columns {
+                if let Some(x) = prices.get(c) {
+                    m.insert(c.clone(), (*x).into());
+                }
+            }
+            format!("{}\n", serde_json::Value::Object(m))
+        }
+    }
+}
+
+/// Reader of the response body, formatting the rows as they are received
+pub struct RowsReader {
+    rows: Receiver<Result<PriceRow>>,
+    format: Format,
+    columns: Vec<String>,
+    buf: Vec<u8>,
+    pos: usize,
+    rows_sent: usize,
+    finished: bool,
+}
+
+impl RowsReader {
+    pub fn new(rows: Receiver<Result<PriceRow>>, format: Format, columns: Vec<String>) -> Self {
+        let buf = header(format, &columns).into_bytes();
+        Self {
+            rows,
+            format,
+            columns,
+            buf,
+            pos: 0,
+            rows_sent: 0,
+            finished: false,
+        }
+    }
+}
+
+impl BufRead for RowsReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.pos >= this.buf.len() {
+            if this.finished {
+                return Poll::Ready(Ok(&[]));
+            }
+            match Pin::new(&mut this.rows).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(row))) => {
+                    let mut out = line(this.format, &this.columns, &row);
+                    if this.format == Format::Json && this.rows_sent > 0 {
+                        out.insert(0, ',');
+                    }
+                    this.buf = out.into_bytes();
+                    this.pos = 0;
+                    this.rows_sent += 1;
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    // headers are already sent, the output is cut here
+                    warn!("stream failure: {}", e);
+                    return Poll::Ready(Err(std::io::Error::other(e.to_string())));
+                }
+                Poll::Ready(None) => {
+                    this.buf = footer(this.format).into_bytes();
+                    this.pos = 0;
+                    this.finished = true;
+                }
+            }
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = (this.pos + amt).min(this.buf.len());
+    }
+}
+
+impl Read for RowsReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Ready(Ok(buf)) => {
+                let n = buf.len().min(out.len());
+                out[..n].copy_from_slice(&buf[..n]);
+                n
+            }
+        };
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// response body streaming the rows in the requested format
+pub fn body(rows: Receiver<Result<PriceRow>>, format: Format, columns: Vec<String>) -> Body {
+    let mut body = Body::from_reader(RowsReader::new(rows, format, columns), None);
+    body.set_mime(format.mime());
+    body
+}
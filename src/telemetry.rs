@@ -7,6 +7,10 @@ use tracing_futures::Instrument;
 const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
 const RQID_LEN: usize = 8;
 
+/// Id of the request, available to the handlers as the request extension
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
 /// Log all incoming requests and responses with tracing spans.
 ///
 /// ```
@@ -26,7 +30,7 @@ impl TraceMiddleware {
     /// Log a request and a response.
     async fn log<'a, State: Clone + Send + Sync + 'static>(
         &'a self,
-        ctx: Request<State>,
+        mut ctx: Request<State>,
         next: Next<'a, State>,
     ) -> tide::Result {
         let path = ctx.url().path().to_owned();
@@ -77,9 +81,11 @@ impl TraceMiddleware {
         .collect::<Vec<String>>()
         .join(",");
 
+        ctx.set_ext(RequestId(rqid.clone()));
         Ok(async {
             let start = Instant::now();
-            let response = next.run(ctx).await;
+            let mut response = next.run(ctx).await;
+            response.insert_header("x-request-id", rqid.as_str());
             let duration = start.elapsed();
             let status = response.status();
 
@@ -0,0 +1,353 @@
+use crate::api;
+use crate::error::ApiError;
+use crate::resolve;
+use crate::{db, live, Fields, State};
+use async_std::future::timeout;
+use async_std::prelude::*;
+use async_std::task;
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::protocol::Role;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use chrono::Utc;
+use futures::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use tide::http::upgrade::Connection;
+use tide::{Request, Response, StatusCode};
+use tracing::{info, warn};
+
+/// most pairs one connection may subscribe to
+const MAX_PAIRS: usize = 1000;
+
+/// Market and fiat currency of the subscription
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct Pair {
+    pub market: String,
+    pub currency: String,
+}
+
+/// Messages of the client, see the protocol in README
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        id: Option<String>,
+        pairs: Vec<Pair>,
+    },
+    Unsubscribe {
+        id: Option<String>,
+        pairs: Vec<Pair>,
+    },
+    Replay {
+        id: Option<String>,
+        market: String,
+        currencies: Option<Vec<String>>,
+        from: String,
+        to: String,
+    },
+}
+
+/// Messages of the server, see the protocol in README
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed {
+        id: Option<String>,
+        pairs: Vec<Pair>,
+    },
+    Price {
+        market: String,
+        currency: String,
+        price: f64,
+        ts: i64,
+    },
+    History {
+        id: Option<String>,
+        market: String,
+        date: String,
+        prices: BTreeMap<String, f64>,
+    },
+    ReplayDone {
+        id: Option<String>,
+        rows: usize,
+    },
+    Heartbeat {
+        ts: i64,
+    },
+    Error {
+        id: Option<String>,
+        code: &'static str,
+        message: String,
+    },
+}
+
+fn error(id: Option<String>, e: ApiError) -> ServerMessage {
+    ServerMessage::Error {
+        id,
+        code: e.code,
+        message: e.message,
+    }
+}
+
+/// Connection of the client with its subscriptions
+struct Session {
+    state: State,
+    ws: WebSocketStream<Connection>,
+    pairs: BTreeSet<Pair>,
+    /// last sent prices of the pairs
+    last: HashMap<Pair, f64>,
+}
+
+impl Session {
+    async fn send(&mut self, msg: &ServerMessage) -> anyhow::Result<()> {
+        let text = serde_json::to_string(msg)?;
+        self.ws.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    /// pairs with the configured market names and currencies
+    fn resolve(&self, pairs: Vec<Pair>) -> Result<Vec<Pair>, ApiError> {
+        let state = &self.state;
+        pairs
+            .into_iter()
+            .map(|p| {
+                let market = resolve::market(&state.markets, &state.aliases, &p.market)?;
+                let currency = p.currency.to_lowercase();
+                if !state.currencies.contains(&currency) {
+                    let msg = format!("currency {} is not configured", p.currency);
+                    return Err(ApiError::input(&msg));
+                }
+                Ok(Pair {
+                    market: market.name,
+                    currency,
+                })
+            })
+            .collect()
+    }
+
+    /// handles the message of the client, returns whether the prices should be sent now
+    async fn handle(&mut self, text: &str) -> anyhow::Result<bool> {
+        let msg: ClientMessage = match serde_json::from_str(text) {
+            Ok(x) => x,
+            Err(e) => {
+                self.send(&error(None, ApiError::input(&e.to_string())))
+                    .await?;
+                return Ok(false);
+            }
+        };
+        match msg {
+            ClientMessage::Subscribe { id, pairs } => {
+                let pairs = match self.resolve(pairs) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        self.send(&error(id, e)).await?;
+                        return Ok(false);
+                    }
+                };
+                let added: BTreeSet<Pair> = pairs.into_iter().collect();
+                if self.pairs.union(&added).count() > MAX_PAIRS {
+                    let msg = format!("more than {} pairs", MAX_PAIRS);
+                    self.send(&error(id, ApiError::input(&msg))).await?;
+                    return Ok(false);
+                }
+                for pair in added {
+                    // the current price of the new pair is sent right away
+                    self.last.remove(&pair);
+                    self.pairs.insert(pair);
+                }
+                let pairs = self.pairs.iter().cloned().collect();
+                self.send(&ServerMessage::Subscribed { id, pairs }).await?;
+                Ok(true)
+            }
+            ClientMessage::Unsubscribe { id, pairs } => {
+                let pairs = match self.resolve(pairs) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        self.send(&error(id, e)).await?;
+                        return Ok(false);
+                    }
+                };
+                for pair in pairs {
+                    self.last.remove(&pair);
+                    self.pairs.remove(&pair);
+                }
+                let pairs = self.pairs.iter().cloned().collect();
+                self.send(&ServerMessage::Subscribed { id, pairs }).await?;
+                Ok(false)
+            }
+            ClientMessage::Replay {
+                id,
+                market,
+                currencies,
+                from,
+                to,
+            } => {
+                if let Err(e) = self
+                    .replay(id.clone(), &market, currencies, &from, &to)
+                    .await
+                {
+                    self.send(&error(id, e)).await?;
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// sends the stored daily prices of the period, gaps of the history are skipped
+    async fn replay(
+        &mut self,
+        id: Option<String>,
+        market: &str,
+        currencies: Option<Vec<String>>,
+        from: &str,
+        to: &str,
+    ) -> Result<(), ApiError> {
+        let state = self.state.clone();
+        let market = resolve::market(&state.markets, &state.aliases, market)?.name;
+        let currencies = match currencies {
+            Some(list) => state
+                .currencies
+                .select(&list.join(","))
+                .map_err(|e| ApiError::input(&e))?,
+            None => state.currencies.clone(),
+        };
+        let tm_from = api::day_start(from).map_err(|e| ApiError::input(&e))?;
+        let tm_to = api::day_start(to).map_err(|e| ApiError::input(&e))?;
+        if (tm_to - tm_from).num_days() >= state.max_period_days {
+            let msg = format!("period is longer than {} days", state.max_period_days);
+            return Err(ApiError::input(&msg));
+        }
+        info!("replay market={} from={} to={}", market, from, to);
+
+        let fields = Fields::default();
+        let rows = db::stream_prices_period(
+            state.db_pool.clone(),
+            tm_from,
+            tm_to,
+            &market,
+            &currencies,
+            &fields,
+        );
+        let mut count = 0;
+        while let Ok(row) = rows.recv().await {
+            let (date, prices) = row.map_err(|e| ApiError::internal(&e.to_string()))?;
+            let prices = prices.into_iter().filter(|(_, v)| *v > 0.0).collect();
+            let msg = ServerMessage::History {
+                id: id.clone(),
+                market: market.clone(),
+                date,
+                prices,
+            };
+            self.send(&msg)
+                .await
+                .map_err(|e| ApiError::internal(&e.to_string()))?;
+            count += 1;
+        }
+        self.send(&ServerMessage::ReplayDone { id, rows: count })
+            .await
+            .map_err(|e| ApiError::internal(&e.to_string()))
+    }
+
+    /// sends the prices of the subscribed pairs that changed, heartbeat when none did
+    async fn check(&mut self) -> anyhow::Result<()> {
+        let ts = Utc::now().timestamp();
+        let mut updates: Vec<(Pair, f64)> = vec![];
+        if !self.pairs.is_empty() {
+            let snapshot = live::snapshot(&self.state).await?;
+            for pair in &self.pairs {
+                let price = match snapshot
+                    .get(&pair.market)
+                    .and_then(|x| x.get(&pair.currency))
+                {
+                    Some(x) => *x,
+                    None => continue,
+                };
+                if self.last.get(pair) != Some(&price) {
+                    updates.push((pair.clone(), price));
+                }
+            }
+        }
+        if updates.is_empty() {
+            return self.send(&ServerMessage::Heartbeat { ts }).await;
+        }
+        for (pair, price) in updates {
+            self.last.insert(pair.clone(), price);
+            let msg = ServerMessage::Price {
+                market: pair.market,
+                currency: pair.currency,
+                price,
+                ts,
+            };
+            self.send(&msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn run(mut self) -> anyhow::Result<()> {
+        let interval = Duration::from_secs(self.state.stream_interval);
+        let mut next_check = Instant::now() + interval;
+        loop {
+            let wait = next_check.saturating_duration_since(Instant::now());
+            let msg = match timeout(wait, self.ws.next()).await {
+                Ok(Some(x)) => x?,
+                Ok(None) => return Ok(()),
+                Err(_) => {
+                    self.check().await?;
+                    next_check = Instant::now() + interval;
+                    continue;
+                }
+            };
+            let check_now = match msg {
+                Message::Text(text) => self.handle(&text).await?,
+                Message::Close(_) => return Ok(()),
+                // pings are answered by the protocol, binary frames are not expected
+                _ => false,
+            };
+            if check_now {
+                next_check = Instant::now();
+            }
+        }
+    }
+}
+
+/// upgrades the connection to the WebSocket session
+pub async fn connect(req: Request<State>) -> tide::Result {
+    let is_websocket = match req.header("upgrade") {
+        Some(x) => x.as_str().eq_ignore_ascii_case("websocket"),
+        None => false,
+    };
+    let key = match req.header("sec-websocket-key") {
+        Some(x) if is_websocket => x.as_str().to_owned(),
+        _ => {
+            let msg = "websocket upgrade is expected";
+            let e = ApiError::new(StatusCode::UpgradeRequired, "upgrade_required", msg);
+            return Err(e.into());
+        }
+    };
+    let mut res = Response::new(StatusCode::SwitchingProtocols);
+    res.insert_header("upgrade", "websocket");
+    res.insert_header("connection", "Upgrade");
+    res.insert_header("sec-websocket-accept", derive_accept_key(key.as_bytes()));
+    let http_res: &mut tide::http::Response = res.as_mut();
+    let upgrade = http_res.recv_upgrade().await;
+
+    let state = req.state().clone();
+    task::spawn(async move {
+        let conn = match upgrade.await {
+            Some(x) => x,
+            None => return,
+        };
+        let session = Session {
+            state,
+            ws: WebSocketStream::from_raw_socket(conn, Role::Server, None).await,
+            pairs: BTreeSet::new(),
+            last: HashMap::new(),
+        };
+        if let Err(e) = session.run().await {
+            warn!("websocket session: {}", e);
+        }
+    });
+    Ok(res)
+}